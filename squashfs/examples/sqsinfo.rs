@@ -0,0 +1,41 @@
+//! Print the superblock and the tree of an archive.
+//!
+//! ```text
+//! RUST_LOG=debug cargo run --example sqsinfo -- image.sqs
+//! ```
+
+use flexi_logger::{colored_opt_format, Logger};
+use squashfs::{SquashFs, WalkOrder};
+use std::fs::File;
+use std::io::Result;
+
+fn main() -> Result<()> {
+  // the library only logs through the `log` facade, the application picks the logger
+  let _logger = Logger::try_with_env_or_str("info")
+    .and_then(|logger| logger.format(colored_opt_format).start())
+    .map_err(std::io::Error::other)?;
+
+  let path = match std::env::args().nth(1) {
+    Some(path) => path,
+    None => {
+      eprintln!("usage: sqsinfo <image>");
+      std::process::exit(2);
+    }
+  };
+
+  let mut archive = SquashFs::new(File::open(path)?)?;
+  archive.superblock().to_table().printstd();
+  for entry in archive.walk(WalkOrder::DepthFirst) {
+    let entry = entry?;
+    let header = entry.header();
+    println!(
+      "{:?} {:o} {} {}",
+      header.inode_type,
+      header.permissions,
+      header.inode_number,
+      entry.path_lossy()
+    );
+  }
+
+  Ok(())
+}
@@ -0,0 +1,225 @@
+use super::*;
+use crate::compress::CompressorOptions;
+use std::io::{Read, Result, Seek, SeekFrom};
+
+/// A squashfs archive opened from any seekable reader.
+///
+/// The superblock is loaded and checked when the archive is opened, the
+/// fragment, id and xattr tables are read on first use and cached.
+pub struct SquashFs<R: Read + Seek> {
+  reader: R,
+  superblock: Superblock,
+  fragments: Option<FragmentsTab>,
+  ids: Option<Vec<u32>>,
+  xattrs: Option<Option<XAttrTable>>,
+  exports: Option<Option<ExportTab>>,
+}
+
+impl<R: Read + Seek> SquashFs<R> {
+  /// Open an archive, the image must start at offset 0 of the reader.
+  /// The superblock is validated, see `Superblock::validate`.
+  pub fn new(mut reader: R) -> Result<Self> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut superblock = Superblock::new();
+    superblock.load(&mut reader)?;
+
+    Ok(SquashFs {
+      reader,
+      superblock,
+      fragments: None,
+      ids: None,
+      xattrs: None,
+      exports: None,
+    })
+  }
+
+  pub fn superblock(&self) -> &Superblock {
+    &self.superblock
+  }
+
+  /// The options stored after the superblock, `None` if the archive uses the defaults.
+  pub fn compressor_options(&self) -> Option<&CompressorOptions> {
+    self.superblock.compressor_options.as_ref()
+  }
+
+  /// Give back the underlying reader.
+  pub fn into_inner(self) -> R {
+    self.reader
+  }
+
+  pub fn fragments(&mut self) -> Result<&FragmentsTab> {
+    if self.fragments.is_none() {
+      self.fragments = Some(read_fragment_table(&mut self.reader, &self.superblock)?);
+    }
+    Ok(self.fragments.as_ref().unwrap())
+  }
+
+  /// The uid/gid lookup table.
+  pub fn ids(&mut self) -> Result<&[u32]> {
+    if self.ids.is_none() {
+      self.ids = Some(read_lookup_table(&mut self.reader, &self.superblock)?);
+    }
+    Ok(self.ids.as_ref().unwrap())
+  }
+
+  /// The xattr id table, `None` if the archive has no xattrs.
+  pub fn xattr_table(&mut self) -> Result<Option<&XAttrTable>> {
+    if self.xattrs.is_none() {
+      self.xattrs = Some(read_xattrs_table(&mut self.reader, &self.superblock)?);
+    }
+    Ok(self.xattrs.as_ref().unwrap().as_ref())
+  }
+
+  /// The extended attributes of an inode, e.g. `security.selinux` or `security.capability`.
+  pub fn xattrs(&mut self, inode: &Inode) -> Result<Vec<XAttr>> {
+    let idx = match inode.xattr_idx() {
+      Some(idx) => idx,
+      None => return Ok(vec![]),
+    };
+    self.xattr_table()?;
+    let table = self.xattrs.as_ref().unwrap().as_ref().ok_or_else(|| {
+      invalid_error!(format!(
+        "inode {} has xattrs but the archive has no xattr table",
+        inode.header().inode_number
+      ))
+    })?;
+    read_xattrs(&mut self.reader, &self.superblock, table, idx)
+  }
+
+  /// The export table, `None` if the archive is not exportable.
+  pub fn export_table(&mut self) -> Result<Option<&ExportTab>> {
+    if self.exports.is_none() {
+      self.exports = Some(read_export_table(&mut self.reader, &self.superblock)?);
+    }
+    Ok(self.exports.as_ref().unwrap().as_ref())
+  }
+
+  /// Find the reference of an inode from its number, without walking the tree.
+  pub fn inode_by_number(&mut self, inode_number: u32) -> Result<InodeRef> {
+    let table = self.export_table()?.ok_or_else(|| {
+      unsupported_error!("the archive has no export table, it was built with -no-exports")
+    })?;
+    inode_number
+      .checked_sub(1)
+      .and_then(|idx| table.get(idx as usize))
+      .copied()
+      .ok_or_else(|| not_found_error!(format!("inode number {}", inode_number)))
+  }
+
+  /// Resolve an index of the id table, e.g. `InodeHeader::uid_idx`.
+  pub fn id(&mut self, idx: u16) -> Result<u32> {
+    self.ids()?.get(idx as usize).copied().ok_or_else(|| {
+      invalid_error!(format!("id index {} out of the lookup table", idx))
+    })
+  }
+
+  pub fn inode(&mut self, inode_ref: InodeRef) -> Result<Inode> {
+    get_inode(&mut self.reader, &self.superblock, inode_ref)
+  }
+
+  pub fn root(&mut self) -> Result<Inode> {
+    let root = self.superblock.root_inode_ref;
+    self.inode(root)
+  }
+
+  /// List a directory inode.
+  pub fn read_dir(&mut self, inode: &Inode) -> Result<DirEntries> {
+    let dir = inode
+      .as_directory()
+      .ok_or_else(|| not_a_directory_error!(format!("inode {}", inode.header().inode_number)))?;
+    read_directory(&mut self.reader, &self.superblock, dir)
+  }
+
+  /// Find the inode at an absolute path, e.g. `/usr/lib/os-release`, following symlinks.
+  pub fn lookup(&mut self, path: &str) -> Result<Inode> {
+    self.lookup_with(path, LookupOptions::default())
+  }
+
+  pub fn lookup_with(&mut self, path: &str, options: LookupOptions) -> Result<Inode> {
+    lookup(&mut self.reader, &self.superblock, path, options)
+  }
+
+  /// Iterate over every node of the archive.
+  pub fn walk(&mut self, order: WalkOrder) -> Walk<'_> {
+    Walk::new(&mut self.reader, &self.superblock, order)
+  }
+
+  /// Read the content of a regular file inode.
+  pub fn file_reader(&mut self, inode: &Inode) -> Result<FileReader<'_>> {
+    let file = inode.as_file().ok_or_else(|| {
+      not_a_file_error!(format!("inode {}", inode.header().inode_number))
+    })?;
+
+    let fragment = if file.fragment_block_idx() == NO_FRAGMENT {
+      None
+    } else {
+      let idx = file.fragment_block_idx();
+      let entry = self.fragments()?.entries.get(idx as usize).cloned();
+      Some(entry.ok_or_else(|| invalid_error!(format!("fragment {} out of the table", idx)))?)
+    };
+
+    FileReader::new(&mut self.reader, &self.superblock, file, fragment)
+  }
+
+  /// Open the regular file at an absolute path for reading.
+  pub fn open(&mut self, path: &str) -> Result<FileReader<'_>> {
+    let inode = self.lookup(path)?;
+    self.file_reader(&inode)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::tests::*;
+  use crate::*;
+  use std::io::{Cursor, ErrorKind, Read, Result};
+
+  #[test]
+  fn test_open_archive() -> Result<()> {
+    let mut sqs = SquashFs::new(Cursor::new(build_test_image()))?;
+    assert_eq!(sqs.superblock().inode_count, 6);
+    assert!(sqs.compressor_options().is_none());
+    assert_eq!(sqs.ids()?, &[1000]);
+    assert!(sqs.xattr_table()?.is_none());
+    assert!(sqs.fragments()?.entries.is_empty());
+
+    let root = sqs.root()?;
+    assert!(sqs.xattrs(&root)?.is_empty());
+    let names = sqs
+      .read_dir(&root)?
+      .map(|e| e.map(|e| e.name_lossy()))
+      .collect::<Result<Vec<_>>>()?;
+    assert_eq!(names, vec!["hello", "link", "loop", "sub"]);
+
+    let deep = sqs.lookup("/sub/deep")?;
+    assert_eq!(deep.header().inode_number, 3);
+    let deep_ref = sqs.inode_by_number(3)?;
+    assert_eq!(sqs.inode(deep_ref)?.header().inode_number, 3);
+    assert!(sqs.inode_by_number(0).is_err());
+    assert!(sqs.inode_by_number(7).is_err());
+    assert_eq!(sqs.id(deep.header().uid_idx)?, 1000);
+
+    let mut content = String::new();
+    sqs.open("/hello")?.read_to_string(&mut content)?;
+    assert_eq!(content, "hello world\n");
+    let err = sqs.open("/sub").err().unwrap();
+    assert!(matches!(SquashfsError::from(err), SquashfsError::NotAFile(_)));
+    assert_eq!(
+      sqs.lookup("/nothing").unwrap_err().kind(),
+      ErrorKind::NotFound
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_open_bad_magic() {
+    let mut image = build_test_image();
+    image[0] = 0;
+    let err = SquashFs::new(Cursor::new(image)).err().unwrap();
+    assert!(matches!(
+      SquashfsError::from(err),
+      SquashfsError::BadMagic(0x7371_7300)
+    ));
+  }
+}
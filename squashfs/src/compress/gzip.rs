@@ -0,0 +1,58 @@
+use super::*;
+use flate2::bufread::{GzDecoder, ZlibDecoder};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::Write;
+
+/// Blocks are zlib streams, which the kernel expects. The window size and
+/// strategies of the options are not supported by the deflate backend and
+/// only the level is used.
+pub fn compress(raw: &[u8], options: &GzipOptions) -> Result<Vec<u8>> {
+  let out: Vec<u8> = Vec::new();
+  let mut zlib = ZlibEncoder::new(out, Compression::new(options.compression_level));
+  zlib.write_all(raw)?;
+  zlib.finish()
+}
+
+/// ZLIB/GZIP headers
+/// Level | ZLIB  | GZIP
+///  1   | 78 01 | 1F 8B
+///  2   | 78 5E | 1F 8B
+///  3   | 78 5E | 1F 8B
+///  4   | 78 5E | 1F 8B
+///  5   | 78 5E | 1F 8B
+///  6   | 78 9C | 1F 8B
+///  7   | 78 DA | 1F 8B
+///  8   | 78 DA | 1F 8B
+///  9   | 78 DA | 1F 8B
+pub fn decompress(raw: &[u8], output: &mut [u8]) -> Result<usize> {
+  trace!(
+    "[decompress] Gzip header={:X?} isZlib={}",
+    &raw[0..1],
+    raw[0..1] == [0x78]
+  );
+  match raw[0..1] {
+    [0x78] => read_full(&mut ZlibDecoder::new(raw), output),
+    _ => read_full(&mut GzDecoder::new(raw), output),
+  }
+}
+
+pub struct GzipCompressor;
+
+impl Compressor for GzipCompressor {
+  fn algorithm(&self) -> Algorithm {
+    Algorithm::Gzip
+  }
+
+  fn compress(&self, raw: &[u8], options: Option<&CompressorOptions>) -> Result<Vec<u8>> {
+    match options {
+      Some(CompressorOptions::Gzip(options)) => compress(raw, options),
+      Some(options) => Err(mismatched_options(self.algorithm(), options)),
+      None => compress(raw, &GzipOptions::default()),
+    }
+  }
+
+  fn decompress(&self, raw: &[u8], output: &mut [u8]) -> Result<usize> {
+    decompress(raw, output)
+  }
+}
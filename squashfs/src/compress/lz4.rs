@@ -0,0 +1,39 @@
+use super::*;
+use ::lz4::block::{self, CompressionMode};
+
+/// Level used by mksquashfs for `LZ4HC`.
+pub const LZ4HC_DEFAULT_LEVEL: i32 = 9;
+
+/// Blocks are stored in the raw lz4 block format, without frame or size prefix.
+pub fn compress(raw: &[u8], options: &Lz4Options) -> Result<Vec<u8>> {
+  let mode = if options.flags.contains(Lz4Flags::HC) {
+    CompressionMode::HIGHCOMPRESSION(LZ4HC_DEFAULT_LEVEL)
+  } else {
+    CompressionMode::DEFAULT
+  };
+  block::compress(raw, Some(mode), false)
+}
+
+pub fn decompress(raw: &[u8], output: &mut [u8]) -> Result<usize> {
+  block::decompress_to_buffer(raw, Some(output.len() as i32), output)
+}
+
+pub struct Lz4Compressor;
+
+impl Compressor for Lz4Compressor {
+  fn algorithm(&self) -> Algorithm {
+    Algorithm::Lz4
+  }
+
+  fn compress(&self, raw: &[u8], options: Option<&CompressorOptions>) -> Result<Vec<u8>> {
+    match options {
+      Some(CompressorOptions::Lz4(options)) => compress(raw, options),
+      Some(options) => Err(mismatched_options(self.algorithm(), options)),
+      None => compress(raw, &Lz4Options::default()),
+    }
+  }
+
+  fn decompress(&self, raw: &[u8], output: &mut [u8]) -> Result<usize> {
+    decompress(raw, output)
+  }
+}
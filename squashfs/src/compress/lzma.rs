@@ -0,0 +1,28 @@
+use super::*;
+use crate::{map_other_error, unsupported_error};
+use xz2::read::XzDecoder;
+use xz2::stream::Stream;
+
+/// Legacy lzma blocks use the lzma-alone format: a 13 bytes header with the properties
+/// and the uncompressed size, followed by the raw lzma stream.
+pub fn decompress(raw: &[u8], output: &mut [u8]) -> Result<usize> {
+  let stream = Stream::new_lzma_decoder(u64::MAX).map_err(|e| map_other_error!(e))?;
+  read_full(&mut XzDecoder::new_stream(raw, stream), output)
+}
+
+/// Only reading is supported, mksquashfs dropped lzma in favour of xz.
+pub struct LzmaCompressor;
+
+impl Compressor for LzmaCompressor {
+  fn algorithm(&self) -> Algorithm {
+    Algorithm::Lzma
+  }
+
+  fn compress(&self, _raw: &[u8], _options: Option<&CompressorOptions>) -> Result<Vec<u8>> {
+    Err(unsupported_error!("lzma compression is not supported"))
+  }
+
+  fn decompress(&self, raw: &[u8], output: &mut [u8]) -> Result<usize> {
+    decompress(raw, output)
+  }
+}
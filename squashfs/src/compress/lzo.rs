@@ -0,0 +1,153 @@
+use super::*;
+use crate::unsupported_error;
+
+/// Matches with a distance above `M2_MAX_OFFSET` after a literal run are M1 matches of 3 bytes.
+const M2_MAX_OFFSET: usize = 0x0800;
+
+/// A LZO1X decoder with bounds checks, same state machine as `lzo1x_decompress_safe`.
+/// Every algorithm of the LZO1X family (1, 1_11, 1_12, 1_15, 999) produces this format.
+struct Decoder<'a> {
+  input: &'a [u8],
+  ip: usize,
+  output: &'a mut [u8],
+  op: usize,
+}
+
+impl Decoder<'_> {
+  fn byte(&mut self) -> Result<usize> {
+    let b = *self
+      .input
+      .get(self.ip)
+      .ok_or_else(|| invalid_error!("lzo input overrun"))?;
+    self.ip += 1;
+    Ok(b as usize)
+  }
+
+  fn le16(&mut self) -> Result<usize> {
+    Ok(self.byte()? | self.byte()? << 8)
+  }
+
+  /// `base` plus 255 for each zero byte plus the first non-zero byte.
+  fn length(&mut self, base: usize) -> Result<usize> {
+    let mut length = base;
+    loop {
+      match self.byte()? {
+        0 => length += 255,
+        b => return Ok(length + b),
+      }
+    }
+  }
+
+  fn literals(&mut self, count: usize) -> Result<()> {
+    let input = self
+      .input
+      .get(self.ip..self.ip + count)
+      .ok_or_else(|| invalid_error!("lzo input overrun"))?;
+    let output = self
+      .output
+      .get_mut(self.op..self.op + count)
+      .ok_or_else(|| invalid_error!("lzo output overrun"))?;
+    output.copy_from_slice(input);
+    self.ip += count;
+    self.op += count;
+    Ok(())
+  }
+
+  /// copy `count` bytes from `distance` bytes back, the ranges may overlap.
+  fn copy_match(&mut self, distance: usize, count: usize) -> Result<()> {
+    if distance == 0 || distance > self.op {
+      return Err(invalid_error!(format!(
+        "lzo match distance {} before the start of output",
+        distance
+      )));
+    }
+    if self.op + count > self.output.len() {
+      return Err(invalid_error!("lzo output overrun"));
+    }
+    for _ in 0..count {
+      self.output[self.op] = self.output[self.op - distance];
+      self.op += 1;
+    }
+    Ok(())
+  }
+
+  fn run(&mut self) -> Result<usize> {
+    // number of literals copied after the last instruction, 4 after a literal run
+    let mut state = 0;
+
+    if self.input.first().is_some_and(|b| *b > 17) {
+      let count = self.byte()? - 17;
+      self.literals(count)?;
+      state = count.min(4);
+    }
+
+    loop {
+      let t = self.byte()?;
+      let (distance, count, next) = if t < 16 {
+        match state {
+          0 => {
+            let count = if t == 0 { self.length(15)? } else { t };
+            self.literals(count + 3)?;
+            state = 4;
+            continue;
+          }
+          4 => (1 + M2_MAX_OFFSET + (t >> 2) + (self.byte()? << 2), 3, t & 3),
+          _ => (1 + (t >> 2) + (self.byte()? << 2), 2, t & 3),
+        }
+      } else if t >= 64 {
+        (1 + ((t >> 2) & 7) + (self.byte()? << 3), (t >> 5) + 1, t & 3)
+      } else if t >= 32 {
+        let count = match t & 31 {
+          0 => self.length(31)?,
+          n => n,
+        };
+        let next = self.le16()?;
+        (1 + (next >> 2), count + 2, next & 3)
+      } else {
+        let count = match t & 7 {
+          0 => self.length(7)?,
+          n => n,
+        };
+        let next = self.le16()?;
+        let distance = ((t & 8) << 11) + (next >> 2);
+        if distance == 0 {
+          // end of stream marker
+          return Ok(self.op);
+        }
+        (distance + 0x4000, count + 2, next & 3)
+      };
+
+      self.copy_match(distance, count)?;
+      // the low two bits of a match are the number of literals following it
+      self.literals(next)?;
+      state = next;
+    }
+  }
+}
+
+pub fn decompress(raw: &[u8], output: &mut [u8]) -> Result<usize> {
+  let mut decoder = Decoder {
+    input: raw,
+    ip: 0,
+    output,
+    op: 0,
+  };
+  decoder.run()
+}
+
+/// Only reading is supported.
+pub struct LzoCompressor;
+
+impl Compressor for LzoCompressor {
+  fn algorithm(&self) -> Algorithm {
+    Algorithm::Lzo
+  }
+
+  fn compress(&self, _raw: &[u8], _options: Option<&CompressorOptions>) -> Result<Vec<u8>> {
+    Err(unsupported_error!("lzo compression is not supported"))
+  }
+
+  fn decompress(&self, raw: &[u8], output: &mut [u8]) -> Result<usize> {
+    decompress(raw, output)
+  }
+}
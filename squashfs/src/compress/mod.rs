@@ -1,12 +1,42 @@
-use flate2::bufread::{GzDecoder, ZlibDecoder};
-use flate2::write::GzEncoder;
-use flate2::Compression;
 use std::borrow::Cow;
+use std::convert::TryFrom;
 use std::fmt;
-use std::io::{Read, Result, Write};
+use std::io::Result;
+
+use crate::invalid_error;
+
+#[cfg(feature = "gzip")]
+mod gzip;
+mod options;
+mod registry;
+#[cfg(feature = "lz4")]
+mod lz4;
+#[cfg(feature = "lzma")]
+mod lzma;
+#[cfg(feature = "lzo")]
+mod lzo;
+#[cfg(feature = "xz")]
+mod xz;
+#[cfg(feature = "zstd")]
+mod zstd;
+
+#[cfg(feature = "gzip")]
+pub use gzip::GzipCompressor;
+#[cfg(feature = "lz4")]
+pub use lz4::Lz4Compressor;
+#[cfg(feature = "lzma")]
+pub use lzma::LzmaCompressor;
+#[cfg(feature = "lzo")]
+pub use lzo::LzoCompressor;
+pub use options::*;
+pub use registry::*;
+#[cfg(feature = "xz")]
+pub use xz::XzCompressor;
+#[cfg(feature = "zstd")]
+pub use zstd::ZstdCompressor;
 
 #[repr(u16)]
-#[derive(Clone, Copy, Debug, PartialEq, Serialize, SmartDefault)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, SmartDefault)]
 pub enum Algorithm {
   #[default]
   None = 0,
@@ -18,65 +48,250 @@ pub enum Algorithm {
   Zstd,
 }
 
+impl TryFrom<u16> for Algorithm {
+  type Error = std::io::Error;
+
+  fn try_from(id: u16) -> Result<Self> {
+    Ok(match id {
+      1 => Algorithm::Gzip,
+      2 => Algorithm::Lzma,
+      3 => Algorithm::Lzo,
+      4 => Algorithm::Xz,
+      5 => Algorithm::Lz4,
+      6 => Algorithm::Zstd,
+      _ => return Err(invalid_error!(format!("unknown compressor id {}", id))),
+    })
+  }
+}
+
 impl fmt::Display for Algorithm {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "{}", format!("{:?}", self))?;
+    write!(f, "{:?}", self)?;
     Ok(())
   }
 }
 
-pub fn compress(raw: &[u8], algorithm: Algorithm) -> Result<(Cow<[u8]>, bool)> {
-  if raw.len() == 0 {
+/// A block compressor, the builtin ones are registered when their cargo feature
+/// is enabled, others can be plugged in with `register_compressor`.
+pub trait Compressor: Send + Sync {
+  fn algorithm(&self) -> Algorithm;
+
+  /// Compress a data or metadata block, `None` options mean the defaults of mksquashfs.
+  fn compress(&self, raw: &[u8], options: Option<&CompressorOptions>) -> Result<Vec<u8>>;
+
+  /// Decompress a block into `output`, returns the decompressed size.
+  fn decompress(&self, raw: &[u8], output: &mut [u8]) -> Result<usize>;
+
+  /// Parse the options block stored after the superblock.
+  fn parse_options(&self, data: &[u8]) -> Result<Option<CompressorOptions>> {
+    CompressorOptions::parse(self.algorithm(), data)
+  }
+
+  /// Serialize the options block stored after the superblock.
+  fn serialize_options(&self, options: &CompressorOptions) -> Result<Vec<u8>> {
+    Ok(options.to_bytes())
+  }
+}
+
+#[cfg(any(feature = "gzip", feature = "lz4", feature = "xz", feature = "zstd"))]
+fn mismatched_options(algorithm: Algorithm, options: &CompressorOptions) -> std::io::Error {
+  invalid_error!(format!("{} options can't be used with {}", options, algorithm))
+}
+
+/// Compress with the registered compressor and its default options, the data is kept
+/// as is when the algorithm has no compressor, like in `compress_block` otherwise.
+pub fn compress(raw: &[u8], algorithm: Algorithm) -> Result<(Cow<'_, [u8]>, bool)> {
+  if raw.is_empty() || algorithm == Algorithm::None {
     return Ok((Cow::Borrowed(raw), false));
   }
 
-  let compressed = match algorithm {
-    Algorithm::None => return Ok((Cow::Borrowed(raw), false)),
-    Algorithm::Gzip => {
-      let out: Vec<u8> = Vec::new();
-      let mut gz = GzEncoder::new(out, Compression::default());
-      gz.write_all(raw)?;
-      gz.finish()?
-    }
+  let compressor = match get_compressor(algorithm) {
+    Ok(compressor) => compressor,
     // dont compress if algorithm not support
-    _ => return Ok((Cow::Borrowed(raw), false)),
+    Err(_) => return Ok((Cow::Borrowed(raw), false)),
   };
+  compress_block(compressor.as_ref(), None, raw)
+}
 
-  Ok((Cow::Owned(compressed), true))
+/// Compress a block, it is kept as is if compression doesn't make it smaller or the
+/// compressor can't compress. Returns the data and whether it is compressed.
+pub fn compress_block<'a>(
+  compressor: &dyn Compressor,
+  options: Option<&CompressorOptions>,
+  raw: &'a [u8],
+) -> Result<(Cow<'a, [u8]>, bool)> {
+  match compressor.compress(raw, options) {
+    Ok(compressed) if compressed.len() < raw.len() => Ok((Cow::Owned(compressed), true)),
+    Ok(_) => Ok((Cow::Borrowed(raw), false)),
+    Err(e) if e.kind() == std::io::ErrorKind::Unsupported => Ok((Cow::Borrowed(raw), false)),
+    Err(e) => Err(e),
+  }
 }
 
-/// ZLIB/GZIP headers
-/// Level | ZLIB  | GZIP
-///  1   | 78 01 | 1F 8B
-///  2   | 78 5E | 1F 8B
-///  3   | 78 5E | 1F 8B
-///  4   | 78 5E | 1F 8B
-///  5   | 78 5E | 1F 8B
-///  6   | 78 9C | 1F 8B
-///  7   | 78 DA | 1F 8B
-///  8   | 78 DA | 1F 8B
-///  9   | 78 DA | 1F 8B
+/// Decompress with the registered compressor.
 pub fn decompress(raw: &[u8], output: &mut [u8], algorithm: Algorithm) -> Result<usize> {
-  match algorithm {
-    Algorithm::Gzip => {
-      trace!(
-        "[decompress] Gzip header={:X?} isZlib={}",
-        &raw[0..1],
-        &raw[0..1] == &[0x78]
-      );
-      match raw[0..1] {
-        [0x78] => {
-          let mut zlib = ZlibDecoder::new(raw);
-          let size = zlib.read(output)?;
-          Ok(size)
-        }
-        _ => {
-          let mut gzd = GzDecoder::new(raw);
-          gzd.read_exact(output)?;
-          Ok(output.len())
-        }
-      }
+  if raw.is_empty() {
+    return Err(invalid_error!("empty compressed data"));
+  }
+  get_compressor(algorithm)?.decompress(raw, output)
+}
+
+/// read until the output is full or the decoder reaches the end of the stream.
+#[cfg(any(feature = "gzip", feature = "lzma", feature = "xz"))]
+fn read_full(decoder: &mut dyn std::io::Read, output: &mut [u8]) -> Result<usize> {
+  let mut size = 0;
+  while size < output.len() {
+    let n = decoder.read(&mut output[size..])?;
+    if n == 0 {
+      break;
     }
-    _ => Ok(output.len()),
+    size += n;
+  }
+  Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Mutex;
+
+  /// Held by the tests changing the registry and the ones going through LZO in it,
+  /// the registry is shared by the tests running in parallel.
+  static REGISTRY_LOCK: Mutex<()> = Mutex::new(());
+
+  #[cfg(any(feature = "gzip", feature = "lz4", feature = "xz", feature = "zstd"))]
+  fn round_trip(algorithm: Algorithm) -> Result<()> {
+    let raw = b"squashfs squashfs squashfs squashfs squashfs squashfs".repeat(64);
+    let (compressed, is_compressed) = compress(&raw, algorithm)?;
+    assert!(is_compressed);
+    assert!(compressed.len() < raw.len());
+
+    let mut output = vec![0u8; 8192];
+    let size = decompress(&compressed, &mut output, algorithm)?;
+    assert_eq!(&output[..size], &raw[..]);
+    Ok(())
+  }
+
+  #[test]
+  #[cfg(feature = "gzip")]
+  fn test_gzip() -> Result<()> {
+    round_trip(Algorithm::Gzip)
+  }
+
+  #[test]
+  #[cfg(feature = "xz")]
+  fn test_xz() -> Result<()> {
+    round_trip(Algorithm::Xz)
+  }
+
+  #[test]
+  #[cfg(feature = "xz")]
+  fn test_xz_filters() -> Result<()> {
+    let raw = b"squashfs squashfs squashfs squashfs squashfs squashfs".repeat(64);
+    let options = XzOptions {
+      filters: XzFilters::all(),
+      ..Default::default()
+    };
+    let compressed = xz::compress(&raw, &options)?;
+    assert!(compressed.len() < raw.len());
+
+    let mut output = vec![0u8; 8192];
+    let size = decompress(&compressed, &mut output, Algorithm::Xz)?;
+    assert_eq!(&output[..size], &raw[..]);
+    Ok(())
+  }
+
+  #[test]
+  #[cfg(feature = "lz4")]
+  fn test_lz4() -> Result<()> {
+    round_trip(Algorithm::Lz4)
+  }
+
+  #[test]
+  #[cfg(feature = "zstd")]
+  fn test_zstd() -> Result<()> {
+    round_trip(Algorithm::Zstd)
+  }
+
+  #[test]
+  #[cfg(feature = "lzo")]
+  fn test_lzo() -> Result<()> {
+    let _lock = REGISTRY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    // literal run, M3 match overlapping its output, M2 match with a trailing literal, end
+    let raw = [21, b'a', b'b', b'c', b'd', 38, 12, 0, 69, 0, b'z', 17, 0, 0];
+    let mut output = vec![0u8; 32];
+    let size = lzo::decompress(&raw, &mut output)?;
+    assert_eq!(&output[..size], b"abcdabcdabcdcdcz");
+
+    assert!(lzo::decompress(&raw[..8], &mut output).is_err());
+    assert!(lzo::decompress(&raw, &mut output[..8]).is_err());
+    assert_eq!(decompress(&raw, &mut output, Algorithm::Lzo)?, size);
+    Ok(())
+  }
+
+  #[test]
+  #[cfg(feature = "lzma")]
+  fn test_lzma() -> Result<()> {
+    use std::io::Write;
+    use xz2::stream::{LzmaOptions, Stream};
+    use xz2::write::XzEncoder;
+
+    let raw = b"squashfs squashfs squashfs squashfs".repeat(64);
+    let options = LzmaOptions::new_preset(6).map_err(std::io::Error::other)?;
+    let stream = Stream::new_lzma_encoder(&options).map_err(std::io::Error::other)?;
+    let mut lzma = XzEncoder::new_stream(vec![], stream);
+    lzma.write_all(&raw)?;
+    let compressed = lzma.finish()?;
+
+    let mut output = vec![0u8; 8192];
+    let size = decompress(&compressed, &mut output, Algorithm::Lzma)?;
+    assert_eq!(&output[..size], &raw[..]);
+    Ok(())
+  }
+
+  #[test]
+  fn test_unsupported() {
+    let mut output = vec![0u8; 16];
+    let err = decompress(&[0x1, 0x2], &mut output, Algorithm::None).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+  }
+
+  /// registered for lzo while `REGISTRY_LOCK` is held
+  struct Reverse;
+
+  impl Compressor for Reverse {
+    fn algorithm(&self) -> Algorithm {
+      Algorithm::Lzo
+    }
+
+    fn compress(&self, raw: &[u8], _options: Option<&CompressorOptions>) -> Result<Vec<u8>> {
+      Ok(raw.iter().rev().copied().collect())
+    }
+
+    fn decompress(&self, raw: &[u8], output: &mut [u8]) -> Result<usize> {
+      output[..raw.len()].copy_from_slice(raw);
+      output[..raw.len()].reverse();
+      Ok(raw.len())
+    }
+  }
+
+  #[test]
+  fn test_register_compressor() -> Result<()> {
+    let _lock = REGISTRY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let previous = register_compressor(std::sync::Arc::new(Reverse));
+    assert_eq!(get_compressor(Algorithm::Lzo)?.compress(b"abc", None)?, b"cba");
+    // not smaller, so kept as is
+    let (compressed, is_compressed) = compress(b"abc", Algorithm::Lzo)?;
+    assert!(!is_compressed);
+    assert_eq!(&compressed[..], b"abc");
+    let mut output = [0u8; 3];
+    assert_eq!(decompress(b"cba", &mut output, Algorithm::Lzo)?, 3);
+    assert_eq!(&output, b"abc");
+
+    match previous {
+      Some(previous) => register_compressor(previous),
+      None => unregister_compressor(Algorithm::Lzo),
+    };
+    Ok(())
   }
 }
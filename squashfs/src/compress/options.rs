@@ -0,0 +1,514 @@
+use super::*;
+use crate::{read_meta_block, SqsIoRead, Superblock};
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The compressor options are stored in a metadata block right after the superblock.
+pub const COMPRESSOR_OPTIONS_START: u64 = 96;
+
+bitflags! {
+  /// Strategies tried by mksquashfs, the one giving the smallest block is kept
+  #[derive(Default)]
+  pub struct GzipStrategies: u16 {
+    const DEFAULT = 0x0001;
+    const FILTERED = 0x0002;
+    const HUFFMAN_ONLY = 0x0004;
+    const RLE = 0x0008;
+    const FIXED = 0x0010;
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GzipOptions {
+  /// The compression level, 1 to 9
+  pub compression_level: u32,
+
+  /// The log2 of the deflate window size, 8 to 15
+  pub window_size: u16,
+
+  pub strategies: GzipStrategies,
+}
+
+pub const GZIP_OPTIONS_SIZE: usize = 8;
+
+impl Default for GzipOptions {
+  /// Same defaults as mksquashfs.
+  fn default() -> Self {
+    GzipOptions {
+      compression_level: 9,
+      window_size: 15,
+      strategies: GzipStrategies::empty(),
+    }
+  }
+}
+
+impl GzipOptions {
+  pub fn parse(data: &[u8]) -> Result<Self> {
+    if data.len() < GZIP_OPTIONS_SIZE {
+      return Err(invalid_error!(format!(
+        "invalid gzip options size({} bytes), must >= {} bytes",
+        data.len(),
+        GZIP_OPTIONS_SIZE
+      )));
+    }
+    let compression_level = LittleEndian::read_u32(&data[0..4]);
+    let window_size = LittleEndian::read_u16(&data[4..6]);
+    let strategies = LittleEndian::read_u16(&data[6..8]);
+
+    if !(1..=9).contains(&compression_level) {
+      return Err(invalid_error!(format!(
+        "invalid gzip compression level {}",
+        compression_level
+      )));
+    }
+    if !(8..=15).contains(&window_size) {
+      return Err(invalid_error!(format!(
+        "invalid gzip window size {}",
+        window_size
+      )));
+    }
+
+    Ok(GzipOptions {
+      compression_level,
+      window_size,
+      strategies: GzipStrategies::from_bits(strategies)
+        .ok_or_else(|| invalid_error!(format!("unknown gzip strategies {:#x}", strategies)))?,
+    })
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut data = vec![0u8; GZIP_OPTIONS_SIZE];
+    LittleEndian::write_u32(&mut data[0..4], self.compression_level);
+    LittleEndian::write_u16(&mut data[4..6], self.window_size);
+    LittleEndian::write_u16(&mut data[6..8], self.strategies.bits());
+    data
+  }
+}
+
+bitflags! {
+  /// Branch/call/jump filters tried by mksquashfs in addition to plain lzma2.
+  #[derive(Default)]
+  pub struct XzFilters: u32 {
+    const X86 = 0x0001;
+    const POWERPC = 0x0002;
+    const IA64 = 0x0004;
+    const ARM = 0x0008;
+    const ARMTHUMB = 0x0010;
+    const SPARC = 0x0020;
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct XzOptions {
+  /// The dictionary size, a power of two or the sum of two adjacent powers of two
+  pub dictionary_size: u32,
+
+  /// Filters which were tried on each block, the chosen one is stored in the stream itself
+  pub filters: XzFilters,
+}
+
+pub const XZ_OPTIONS_SIZE: usize = 8;
+
+impl Default for XzOptions {
+  /// mksquashfs uses the block size as dictionary size, 128 KiB by default.
+  fn default() -> Self {
+    XzOptions {
+      dictionary_size: 128 * 1024,
+      filters: XzFilters::empty(),
+    }
+  }
+}
+
+impl XzOptions {
+  pub fn parse(data: &[u8]) -> Result<Self> {
+    if data.len() < XZ_OPTIONS_SIZE {
+      return Err(invalid_error!(format!(
+        "invalid xz options size({} bytes), must >= {} bytes",
+        data.len(),
+        XZ_OPTIONS_SIZE
+      )));
+    }
+    let dictionary_size = LittleEndian::read_u32(&data[0..4]);
+    let filters = LittleEndian::read_u32(&data[4..8]);
+
+    if dictionary_size < 8192 {
+      return Err(invalid_error!(format!(
+        "invalid xz dictionary size {}",
+        dictionary_size
+      )));
+    }
+    // n = 2^x or n = 2^x + 2^(x-1)
+    let n = dictionary_size >> dictionary_size.trailing_zeros();
+    if n != 1 && n != 3 {
+      return Err(invalid_error!(format!(
+        "invalid xz dictionary size {}",
+        dictionary_size
+      )));
+    }
+
+    Ok(XzOptions {
+      dictionary_size,
+      filters: XzFilters::from_bits(filters)
+        .ok_or_else(|| invalid_error!(format!("unknown xz filters {:#x}", filters)))?,
+    })
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut data = vec![0u8; XZ_OPTIONS_SIZE];
+    LittleEndian::write_u32(&mut data[0..4], self.dictionary_size);
+    LittleEndian::write_u32(&mut data[4..8], self.filters.bits());
+    data
+  }
+}
+
+bitflags! {
+  #[derive(Default)]
+  pub struct Lz4Flags: u32 {
+    /// Compressed with the high compression mode
+    const HC = 0x0001;
+  }
+}
+
+/// The only lz4 format version, blocks without frame header.
+pub const LZ4_LEGACY: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lz4Options {
+  /// Always `LZ4_LEGACY`
+  pub version: u32,
+
+  pub flags: Lz4Flags,
+}
+
+pub const LZ4_OPTIONS_SIZE: usize = 8;
+
+impl Default for Lz4Options {
+  fn default() -> Self {
+    Lz4Options {
+      version: LZ4_LEGACY,
+      flags: Lz4Flags::empty(),
+    }
+  }
+}
+
+impl Lz4Options {
+  pub fn parse(data: &[u8]) -> Result<Self> {
+    if data.len() < LZ4_OPTIONS_SIZE {
+      return Err(invalid_error!(format!(
+        "invalid lz4 options size({} bytes), must >= {} bytes",
+        data.len(),
+        LZ4_OPTIONS_SIZE
+      )));
+    }
+    let version = LittleEndian::read_u32(&data[0..4]);
+    let flags = LittleEndian::read_u32(&data[4..8]);
+    if version != LZ4_LEGACY {
+      return Err(invalid_error!(format!(
+        "unsupported lz4 format version {}",
+        version
+      )));
+    }
+
+    Ok(Lz4Options {
+      version,
+      flags: Lz4Flags::from_bits(flags)
+        .ok_or_else(|| invalid_error!(format!("unknown lz4 flags {:#x}", flags)))?,
+    })
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut data = vec![0u8; LZ4_OPTIONS_SIZE];
+    LittleEndian::write_u32(&mut data[0..4], self.version);
+    LittleEndian::write_u32(&mut data[4..8], self.flags.bits());
+    data
+  }
+}
+
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, SmartDefault)]
+pub enum LzoAlgorithm {
+  Lzo1x1 = 0,
+  Lzo1x1_11,
+  Lzo1x1_12,
+  Lzo1x1_15,
+  #[default]
+  Lzo1x999,
+}
+
+impl LzoAlgorithm {
+  fn from_u32(value: u32) -> Option<Self> {
+    Some(match value {
+      0 => LzoAlgorithm::Lzo1x1,
+      1 => LzoAlgorithm::Lzo1x1_11,
+      2 => LzoAlgorithm::Lzo1x1_12,
+      3 => LzoAlgorithm::Lzo1x1_15,
+      4 => LzoAlgorithm::Lzo1x999,
+      _ => return None,
+    })
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LzoOptions {
+  pub algorithm: LzoAlgorithm,
+
+  /// The compression level of `Lzo1x999`, 1 to 9, always 0 for the other algorithms
+  pub compression_level: u32,
+}
+
+pub const LZO_OPTIONS_SIZE: usize = 8;
+pub const LZO_MAX_LEVEL: u32 = 9;
+
+impl Default for LzoOptions {
+  /// Same defaults as mksquashfs.
+  fn default() -> Self {
+    LzoOptions {
+      algorithm: LzoAlgorithm::Lzo1x999,
+      compression_level: 8,
+    }
+  }
+}
+
+impl LzoOptions {
+  pub fn parse(data: &[u8]) -> Result<Self> {
+    if data.len() < LZO_OPTIONS_SIZE {
+      return Err(invalid_error!(format!(
+        "invalid lzo options size({} bytes), must >= {} bytes",
+        data.len(),
+        LZO_OPTIONS_SIZE
+      )));
+    }
+    let algorithm = LittleEndian::read_u32(&data[0..4]);
+    let compression_level = LittleEndian::read_u32(&data[4..8]);
+
+    let algorithm = LzoAlgorithm::from_u32(algorithm)
+      .ok_or_else(|| invalid_error!(format!("unknown lzo algorithm {}", algorithm)))?;
+    let valid = match algorithm {
+      LzoAlgorithm::Lzo1x999 => (1..=LZO_MAX_LEVEL).contains(&compression_level),
+      _ => compression_level == 0,
+    };
+    if !valid {
+      return Err(invalid_error!(format!(
+        "invalid lzo compression level {} for {:?}",
+        compression_level, algorithm
+      )));
+    }
+
+    Ok(LzoOptions {
+      algorithm,
+      compression_level,
+    })
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut data = vec![0u8; LZO_OPTIONS_SIZE];
+    LittleEndian::write_u32(&mut data[0..4], self.algorithm as u32);
+    LittleEndian::write_u32(&mut data[4..8], self.compression_level);
+    data
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZstdOptions {
+  /// The compression level, 1 to 22
+  pub compression_level: u32,
+}
+
+pub const ZSTD_OPTIONS_SIZE: usize = 4;
+pub const ZSTD_MAX_LEVEL: u32 = 22;
+
+impl Default for ZstdOptions {
+  /// Same default level as mksquashfs.
+  fn default() -> Self {
+    ZstdOptions {
+      compression_level: 15,
+    }
+  }
+}
+
+impl ZstdOptions {
+  pub fn parse(data: &[u8]) -> Result<Self> {
+    if data.len() < ZSTD_OPTIONS_SIZE {
+      return Err(invalid_error!(format!(
+        "invalid zstd options size({} bytes), must >= {} bytes",
+        data.len(),
+        ZSTD_OPTIONS_SIZE
+      )));
+    }
+    let compression_level = LittleEndian::read_u32(&data[0..4]);
+    if compression_level == 0 || compression_level > ZSTD_MAX_LEVEL {
+      return Err(invalid_error!(format!(
+        "invalid zstd compression level {}",
+        compression_level
+      )));
+    }
+
+    Ok(ZstdOptions { compression_level })
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut data = vec![0u8; ZSTD_OPTIONS_SIZE];
+    LittleEndian::write_u32(&mut data[0..4], self.compression_level);
+    data
+  }
+}
+
+/// Options of the compressor the archive was created with.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompressorOptions {
+  Gzip(GzipOptions),
+  Xz(XzOptions),
+  Lz4(Lz4Options),
+  Lzo(LzoOptions),
+  Zstd(ZstdOptions),
+}
+
+impl CompressorOptions {
+  pub fn parse(algorithm: Algorithm, data: &[u8]) -> Result<Option<Self>> {
+    Ok(match algorithm {
+      Algorithm::Gzip => Some(CompressorOptions::Gzip(GzipOptions::parse(data)?)),
+      Algorithm::Xz => Some(CompressorOptions::Xz(XzOptions::parse(data)?)),
+      Algorithm::Lz4 => Some(CompressorOptions::Lz4(Lz4Options::parse(data)?)),
+      Algorithm::Lzo => Some(CompressorOptions::Lzo(LzoOptions::parse(data)?)),
+      Algorithm::Zstd => Some(CompressorOptions::Zstd(ZstdOptions::parse(data)?)),
+      _ => {
+        debug!("[CompressorOptions] options of {} are ignored", algorithm);
+        None
+      }
+    })
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    match self {
+      CompressorOptions::Gzip(options) => options.to_bytes(),
+      CompressorOptions::Xz(options) => options.to_bytes(),
+      CompressorOptions::Lz4(options) => options.to_bytes(),
+      CompressorOptions::Lzo(options) => options.to_bytes(),
+      CompressorOptions::Zstd(options) => options.to_bytes(),
+    }
+  }
+}
+
+impl fmt::Display for CompressorOptions {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:?}", self)?;
+    Ok(())
+  }
+}
+
+/// read the compressor options if the superblock has the `COMPRESSOR_OPTIONS` flag.
+pub fn read_compressor_options(
+  r: &mut dyn SqsIoRead,
+  sb: &Superblock,
+) -> Result<Option<CompressorOptions>> {
+  if !sb.flags.compressor_options() {
+    return Ok(None);
+  }
+  let (data, _) = read_meta_block(r, sb.compressor, COMPRESSOR_OPTIONS_START)?;
+  trace!("[read_compressor_options] data={:x?}", data);
+  match get_compressor(sb.compressor) {
+    Ok(compressor) => compressor.parse_options(&data),
+    Err(_) => CompressorOptions::parse(sb.compressor, &data),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_gzip_options() -> Result<()> {
+    let raw = [0x9, 0x0, 0x0, 0x0, 0xf, 0x0, 0x3, 0x0];
+    let options = CompressorOptions::parse(Algorithm::Gzip, &raw)?.unwrap();
+    assert_eq!(
+      options,
+      CompressorOptions::Gzip(GzipOptions {
+        compression_level: 9,
+        window_size: 15,
+        strategies: GzipStrategies::DEFAULT | GzipStrategies::FILTERED,
+      })
+    );
+    assert_eq!(options.to_bytes(), raw);
+
+    assert!(GzipOptions::parse(&[0xa, 0x0, 0x0, 0x0, 0xf, 0x0, 0x0, 0x0]).is_err());
+    assert!(GzipOptions::parse(&[0x9, 0x0, 0x0, 0x0, 0x10, 0x0, 0x0, 0x0]).is_err());
+    assert!(GzipOptions::parse(&[0x9, 0x0, 0x0, 0x0, 0xf, 0x0, 0x20, 0x0]).is_err());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_parse_xz_options() -> Result<()> {
+    let raw = [0x0, 0x0, 0x18, 0x0, 0x09, 0x0, 0x0, 0x0];
+    let options = CompressorOptions::parse(Algorithm::Xz, &raw)?.unwrap();
+    assert_eq!(
+      options,
+      CompressorOptions::Xz(XzOptions {
+        dictionary_size: 0x18_0000,
+        filters: XzFilters::X86 | XzFilters::ARM,
+      })
+    );
+    assert_eq!(options.to_bytes(), raw);
+
+    assert!(XzOptions::parse(&[0x0, 0x0, 0x0, 0x05, 0x0, 0x0, 0x0, 0x0]).is_err());
+    assert!(XzOptions::parse(&[0x0, 0x0, 0x10, 0x0, 0x0, 0x01, 0x0, 0x0]).is_err());
+    assert!(XzOptions::parse(&[0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0]).is_err());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_parse_zstd_options() -> Result<()> {
+    let raw = [0x13, 0x0, 0x0, 0x0];
+    let options = CompressorOptions::parse(Algorithm::Zstd, &raw)?.unwrap();
+    assert_eq!(
+      options,
+      CompressorOptions::Zstd(ZstdOptions {
+        compression_level: 19
+      })
+    );
+    assert_eq!(options.to_bytes(), raw);
+
+    assert!(ZstdOptions::parse(&[0x0, 0x0, 0x0, 0x0]).is_err());
+    assert!(ZstdOptions::parse(&[0x17, 0x0, 0x0, 0x0]).is_err());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_parse_lz4_options() -> Result<()> {
+    let raw = [0x1, 0x0, 0x0, 0x0, 0x1, 0x0, 0x0, 0x0];
+    let options = CompressorOptions::parse(Algorithm::Lz4, &raw)?.unwrap();
+    assert_eq!(
+      options,
+      CompressorOptions::Lz4(Lz4Options {
+        version: LZ4_LEGACY,
+        flags: Lz4Flags::HC,
+      })
+    );
+    assert_eq!(options.to_bytes(), raw);
+
+    assert!(Lz4Options::parse(&[0x2, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0]).is_err());
+    assert!(Lz4Options::parse(&[0x1, 0x0, 0x0, 0x0, 0x2, 0x0, 0x0, 0x0]).is_err());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_parse_lzo_options() -> Result<()> {
+    let raw = [0x4, 0x0, 0x0, 0x0, 0x9, 0x0, 0x0, 0x0];
+    let options = CompressorOptions::parse(Algorithm::Lzo, &raw)?.unwrap();
+    assert_eq!(
+      options,
+      CompressorOptions::Lzo(LzoOptions {
+        algorithm: LzoAlgorithm::Lzo1x999,
+        compression_level: 9,
+      })
+    );
+    assert_eq!(options.to_bytes(), raw);
+
+    assert!(LzoOptions::parse(&[0x5, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0]).is_err());
+    assert!(LzoOptions::parse(&[0x0, 0x0, 0x0, 0x0, 0x8, 0x0, 0x0, 0x0]).is_err());
+
+    Ok(())
+  }
+}
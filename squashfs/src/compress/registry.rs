@@ -0,0 +1,65 @@
+use super::*;
+use crate::SquashfsError;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+
+type Registry = RwLock<HashMap<Algorithm, Arc<dyn Compressor>>>;
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+/// The compressors enabled by cargo features.
+fn builtin() -> Vec<Arc<dyn Compressor>> {
+  vec![
+    #[cfg(feature = "gzip")]
+    Arc::new(gzip::GzipCompressor),
+    #[cfg(feature = "lzma")]
+    Arc::new(lzma::LzmaCompressor),
+    #[cfg(feature = "lzo")]
+    Arc::new(lzo::LzoCompressor),
+    #[cfg(feature = "xz")]
+    Arc::new(xz::XzCompressor),
+    #[cfg(feature = "lz4")]
+    Arc::new(lz4::Lz4Compressor),
+    #[cfg(feature = "zstd")]
+    Arc::new(zstd::ZstdCompressor),
+  ]
+}
+
+fn registry() -> &'static Registry {
+  REGISTRY.get_or_init(|| {
+    let compressors = builtin()
+      .into_iter()
+      .map(|c| (c.algorithm(), c))
+      .collect();
+    RwLock::new(compressors)
+  })
+}
+
+/// Use `compressor` for its algorithm from now on, e.g. a hardware accelerated zlib.
+/// Returns the compressor it replaces.
+pub fn register_compressor(compressor: Arc<dyn Compressor>) -> Option<Arc<dyn Compressor>> {
+  let algorithm = compressor.algorithm();
+  debug!("[register_compressor] algorithm={}", algorithm);
+  registry()
+    .write()
+    .unwrap_or_else(|e| e.into_inner())
+    .insert(algorithm, compressor)
+}
+
+/// Remove the compressor of `algorithm`, archives using it can't be read anymore.
+pub fn unregister_compressor(algorithm: Algorithm) -> Option<Arc<dyn Compressor>> {
+  registry()
+    .write()
+    .unwrap_or_else(|e| e.into_inner())
+    .remove(&algorithm)
+}
+
+/// The compressor registered for `algorithm`.
+pub fn get_compressor(algorithm: Algorithm) -> Result<Arc<dyn Compressor>> {
+  registry()
+    .read()
+    .unwrap_or_else(|e| e.into_inner())
+    .get(&algorithm)
+    .cloned()
+    .ok_or_else(|| SquashfsError::UnsupportedCompressor(algorithm).into())
+}
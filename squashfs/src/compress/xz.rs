@@ -0,0 +1,86 @@
+use super::*;
+use crate::map_other_error;
+use xz2::read::XzDecoder;
+use xz2::stream::{Check, Filters, LzmaOptions, Stream};
+use xz2::write::XzEncoder;
+use std::io::Write;
+
+/// Default preset of mksquashfs.
+pub const XZ_PRESET: u32 = 6;
+
+/// The kernel only verifies CRC32 checks, so the stream must not use CRC64.
+/// It also decodes at most one BCJ filter in front of lzma2.
+fn encoder_stream(options: &XzOptions, filter: XzFilters) -> Result<Stream> {
+  let mut lzma = LzmaOptions::new_preset(XZ_PRESET).map_err(|e| map_other_error!(e))?;
+  lzma.dict_size(options.dictionary_size);
+
+  let mut filters = Filters::new();
+  if filter == XzFilters::X86 {
+    filters.x86();
+  } else if filter == XzFilters::POWERPC {
+    filters.powerpc();
+  } else if filter == XzFilters::IA64 {
+    filters.ia64();
+  } else if filter == XzFilters::ARM {
+    filters.arm();
+  } else if filter == XzFilters::ARMTHUMB {
+    filters.arm_thumb();
+  } else if filter == XzFilters::SPARC {
+    filters.sparc();
+  }
+  filters.lzma2(&lzma);
+
+  Stream::new_stream_encoder(&filters, Check::Crc32).map_err(|e| map_other_error!(e))
+}
+
+fn compress_with(raw: &[u8], options: &XzOptions, filter: XzFilters) -> Result<Vec<u8>> {
+  let mut xz = XzEncoder::new_stream(Vec::new(), encoder_stream(options, filter)?);
+  xz.write_all(raw)?;
+  xz.finish()
+}
+
+/// Like mksquashfs, try plain lzma2 and each filter of `options.filters` on its own,
+/// keep the smallest output.
+pub fn compress(raw: &[u8], options: &XzOptions) -> Result<Vec<u8>> {
+  let mut best = compress_with(raw, options, XzFilters::empty())?;
+  let all = [
+    XzFilters::X86,
+    XzFilters::POWERPC,
+    XzFilters::IA64,
+    XzFilters::ARM,
+    XzFilters::ARMTHUMB,
+    XzFilters::SPARC,
+  ];
+  for &filter in all.iter().filter(|&&f| options.filters.contains(f)) {
+    let compressed = compress_with(raw, options, filter)?;
+    if compressed.len() < best.len() {
+      best = compressed;
+    }
+  }
+  Ok(best)
+}
+
+/// The filters of a block are part of the xz stream, the options block is not needed to decode it.
+pub fn decompress(raw: &[u8], output: &mut [u8]) -> Result<usize> {
+  read_full(&mut XzDecoder::new(raw), output)
+}
+
+pub struct XzCompressor;
+
+impl Compressor for XzCompressor {
+  fn algorithm(&self) -> Algorithm {
+    Algorithm::Xz
+  }
+
+  fn compress(&self, raw: &[u8], options: Option<&CompressorOptions>) -> Result<Vec<u8>> {
+    match options {
+      Some(CompressorOptions::Xz(options)) => compress(raw, options),
+      Some(options) => Err(mismatched_options(self.algorithm(), options)),
+      None => compress(raw, &XzOptions::default()),
+    }
+  }
+
+  fn decompress(&self, raw: &[u8], output: &mut [u8]) -> Result<usize> {
+    decompress(raw, output)
+  }
+}
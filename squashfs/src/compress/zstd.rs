@@ -0,0 +1,31 @@
+use super::*;
+use crate::map_other_error;
+
+pub fn compress(raw: &[u8], options: &ZstdOptions) -> Result<Vec<u8>> {
+  ::zstd::bulk::compress(raw, options.compression_level as i32)
+}
+
+/// Each block is a single zstd frame, the level is not needed to decode it.
+pub fn decompress(raw: &[u8], output: &mut [u8]) -> Result<usize> {
+  ::zstd::bulk::decompress_to_buffer(raw, output).map_err(|e| map_other_error!(e))
+}
+
+pub struct ZstdCompressor;
+
+impl Compressor for ZstdCompressor {
+  fn algorithm(&self) -> Algorithm {
+    Algorithm::Zstd
+  }
+
+  fn compress(&self, raw: &[u8], options: Option<&CompressorOptions>) -> Result<Vec<u8>> {
+    match options {
+      Some(CompressorOptions::Zstd(options)) => compress(raw, options),
+      Some(options) => Err(mismatched_options(self.algorithm(), options)),
+      None => compress(raw, &ZstdOptions::default()),
+    }
+  }
+
+  fn decompress(&self, raw: &[u8], output: &mut [u8]) -> Result<usize> {
+    decompress(raw, output)
+  }
+}
@@ -0,0 +1,381 @@
+use super::*;
+use byteorder::{ByteOrder, LittleEndian};
+use std::convert::TryFrom;
+use std::io::Result;
+
+pub const DIRECTORY_HEADER_SIZE: usize = 12;
+pub const DIRECTORY_ENTRY_SIZE: usize = 8;
+/// A directory header is followed by at most 256 entries.
+pub const DIRECTORY_MAX_ENTRIES: u32 = 256;
+/// The longest name of an entry, `SQUASHFS_NAME_LEN` of the kernel.
+pub const DIRECTORY_MAX_NAME_LEN: usize = 256;
+/// The `file_size` of a directory inode counts 3 extra bytes for the implicit `.` and `..` entries.
+pub const DIRECTORY_SIZE_OFFSET: u32 = 3;
+
+/// Directory inodes which point into the directory table.
+pub trait DirectoryInode {
+  /// The offset of the metadata block relative to `Superblock::directory_table_start`
+  fn block_idx(&self) -> u32;
+
+  /// The (uncompressed) offset within the metadata block where the listing starts
+  fn block_offset(&self) -> u16;
+
+  /// Total (uncompressed) size in bytes of the listing, including headers and the 3 bytes of `.`/`..`
+  fn file_size(&self) -> u32;
+
+  /// The inode_number of the parent directory
+  fn parent_inode(&self) -> u32;
+}
+
+impl DirectoryInode for BasicDirectory {
+  fn block_idx(&self) -> u32 {
+    self.block_idx
+  }
+
+  fn block_offset(&self) -> u16 {
+    self.offset
+  }
+
+  fn file_size(&self) -> u32 {
+    self.size as u32
+  }
+
+  fn parent_inode(&self) -> u32 {
+    self.parent_inode
+  }
+}
+
+impl DirectoryInode for ExtendedDirectory {
+  fn block_idx(&self) -> u32 {
+    self.block_idx
+  }
+
+  fn block_offset(&self) -> u16 {
+    self.offset
+  }
+
+  fn file_size(&self) -> u32 {
+    self.size
+  }
+
+  fn parent_inode(&self) -> u32 {
+    self.parent_inode
+  }
+}
+
+/// A run of entries which share the same inode metadata block.
+#[derive(Clone, Default, Debug)]
+pub struct DirectoryHeader {
+  /// The number of entries following the header, minus one
+  pub count: u32,
+
+  /// The offset of the inode metadata block relative to `Superblock::inode_table_start`
+  pub start: u32,
+
+  /// The base inode number, entries store their inode number as a difference to this
+  pub inode_number: u32,
+}
+
+/// A single entry of a directory listing.
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+  /// The name of the entry, not nul terminated and not necessarily UTF-8
+  pub name: Vec<u8>,
+
+  /// The basic type of the inode, extended inodes are listed with their basic type
+  pub inode_type: InodeType,
+
+  /// Where to find the inode in the inode table
+  pub inode_ref: InodeRef,
+
+  /// The number of the inode
+  pub inode_number: u32,
+}
+
+impl DirEntry {
+  pub fn name_lossy(&self) -> String {
+    String::from_utf8_lossy(&self.name).into_owned()
+  }
+
+  pub fn is_dir(&self) -> bool {
+    matches!(
+      self.inode_type,
+      InodeType::BasicDirectory | InodeType::ExtendedDirectory
+    )
+  }
+}
+
+/// Iterator over the entries of a directory listing.
+#[derive(Debug, Default)]
+pub struct DirEntries {
+  data: Vec<u8>,
+  pos: usize,
+  header: DirectoryHeader,
+  remaining: u32,
+}
+
+impl DirEntries {
+  pub fn new(data: Vec<u8>) -> Self {
+    DirEntries {
+      data,
+      ..Default::default()
+    }
+  }
+
+  fn next_entry(&mut self) -> Result<Option<DirEntry>> {
+    if self.remaining == 0 {
+      if self.pos >= self.data.len() {
+        return Ok(None);
+      }
+      self.header = parse_directory_header(&self.data[self.pos..])?;
+      self.pos += DIRECTORY_HEADER_SIZE;
+      self.remaining = self.header.count + 1;
+    }
+
+    let (entry, size) = parse_directory_entry(&self.data[self.pos..], &self.header)?;
+    self.pos += size;
+    self.remaining -= 1;
+
+    Ok(Some(entry))
+  }
+}
+
+impl Iterator for DirEntries {
+  type Item = Result<DirEntry>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    match self.next_entry() {
+      Ok(Some(entry)) => Some(Ok(entry)),
+      Ok(None) => None,
+      Err(e) => {
+        // stop at the first corrupt record
+        self.pos = self.data.len();
+        self.remaining = 0;
+        Some(Err(e))
+      }
+    }
+  }
+}
+
+/// read the listing of a directory.
+/// 1. Get the location of the listing from the directory inode;
+/// 2. Read `file_size - 3` bytes of metadata from the directory table;
+/// 3. Iterate the headers and their entries.
+pub fn read_directory(
+  r: &mut dyn SqsIoRead,
+  sb: &Superblock,
+  dir: &dyn DirectoryInode,
+) -> Result<DirEntries> {
+  if dir.file_size() <= DIRECTORY_SIZE_OFFSET {
+    return Ok(DirEntries::default());
+  }
+  let size = (dir.file_size() - DIRECTORY_SIZE_OFFSET) as usize;
+
+  let mut data = read_metadata(
+    r,
+    sb.compressor,
+    sb.directory_table_start,
+    dir.block_idx(),
+    dir.block_offset() as u32,
+    size,
+  )?;
+  data.truncate(size);
+  trace!("[read_directory] size={}, data={:x?}", size, data);
+
+  Ok(DirEntries::new(data))
+}
+
+pub fn parse_directory_header(data: &[u8]) -> Result<DirectoryHeader> {
+  if data.len() < DIRECTORY_HEADER_SIZE {
+    return Err(invalid_error!(format!(
+      "invalid directory header size({} bytes), must >= {} bytes",
+      data.len(),
+      DIRECTORY_HEADER_SIZE
+    )));
+  }
+
+  let header = DirectoryHeader {
+    count: LittleEndian::read_u32(&data[0..4]),
+    start: LittleEndian::read_u32(&data[4..8]),
+    inode_number: LittleEndian::read_u32(&data[8..12]),
+  };
+  if header.count >= DIRECTORY_MAX_ENTRIES {
+    return Err(invalid_error!(format!(
+      "directory header has {} entries, must <= {}",
+      header.count + 1,
+      DIRECTORY_MAX_ENTRIES
+    )));
+  }
+
+  Ok(header)
+}
+
+impl DirectoryHeader {
+  pub fn to_bytes(&self) -> [u8; DIRECTORY_HEADER_SIZE] {
+    let mut data = [0u8; DIRECTORY_HEADER_SIZE];
+    LittleEndian::write_u32(&mut data[0..4], self.count);
+    LittleEndian::write_u32(&mut data[4..8], self.start);
+    LittleEndian::write_u32(&mut data[8..12], self.inode_number);
+    data
+  }
+}
+
+/// Encode a directory listing, the inverse of `DirEntries`.
+/// A new header starts when the inode metadata block changes, after 256 entries or
+/// when the inode number is too far from the base number of the header.
+pub fn write_directory(entries: &[DirEntry]) -> Vec<u8> {
+  let mut data = vec![];
+  let mut i = 0;
+  while i < entries.len() {
+    let first = &entries[i];
+    let run = entries[i..]
+      .iter()
+      .take(DIRECTORY_MAX_ENTRIES as usize)
+      .take_while(|entry| {
+        let diff = entry.inode_number as i64 - first.inode_number as i64;
+        entry.inode_ref.block() == first.inode_ref.block()
+          && diff >= i16::MIN as i64
+          && diff <= i16::MAX as i64
+      })
+      .count();
+
+    let header = DirectoryHeader {
+      count: run as u32 - 1,
+      start: first.inode_ref.block(),
+      inode_number: first.inode_number,
+    };
+    data.extend(&header.to_bytes());
+    for entry in &entries[i..i + run] {
+      let diff = (entry.inode_number as i64 - header.inode_number as i64) as i16;
+      data.extend(&entry.inode_ref.offset().to_le_bytes());
+      data.extend(&diff.to_le_bytes());
+      data.extend(&(entry.inode_type.basic() as u16).to_le_bytes());
+      data.extend(&(entry.name.len() as u16 - 1).to_le_bytes());
+      data.extend(&entry.name);
+    }
+    i += run;
+  }
+  data
+}
+
+/// returns the entry and the number of bytes it occupies.
+pub fn parse_directory_entry(data: &[u8], header: &DirectoryHeader) -> Result<(DirEntry, usize)> {
+  if data.len() < DIRECTORY_ENTRY_SIZE {
+    return Err(invalid_error!(format!(
+      "invalid directory entry size({} bytes), must >= {} bytes",
+      data.len(),
+      DIRECTORY_ENTRY_SIZE
+    )));
+  }
+
+  let offset = LittleEndian::read_u16(&data[0..2]);
+  let inode_offset = LittleEndian::read_i16(&data[2..4]);
+  let inode_type = InodeType::try_from(LittleEndian::read_u16(&data[4..6]))?;
+  let name_size = LittleEndian::read_u16(&data[6..8]) as usize + 1;
+
+  let end = DIRECTORY_ENTRY_SIZE + name_size;
+  if data.len() < end {
+    return Err(invalid_error!(format!(
+      "directory entry name needs {} bytes, only {} left",
+      name_size,
+      data.len() - DIRECTORY_ENTRY_SIZE
+    )));
+  }
+
+  let entry = DirEntry {
+    name: data[DIRECTORY_ENTRY_SIZE..end].to_vec(),
+    inode_type,
+    inode_ref: InodeRef::new(header.start, offset),
+    inode_number: (header.inode_number as i64 + inode_offset as i64) as u32,
+  };
+  trace!("[parse_directory_entry] entry={:?}", entry);
+
+  Ok((entry, end))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Result;
+
+  #[test]
+  fn test_parse_directory() -> Result<()> {
+    let raw = vec![
+      // header: 2 entries, inode block 0x20, base inode 10
+      0x01, 0x0, 0x0, 0x0, 0x20, 0x0, 0x0, 0x0, 0x0a, 0x0, 0x0, 0x0,
+      // "bin", directory, inode 10 at 0x20:0x60
+      0x60, 0x0, 0x0, 0x0, 0x01, 0x0, 0x02, 0x0, b'b', b'i', b'n',
+      // "sh", symlink, inode 8 at 0x20:0x80
+      0x80, 0x0, 0xfe, 0xff, 0x03, 0x0, 0x01, 0x0, b's', b'h',
+      // header: 1 entry, inode block 0x2000, base inode 20
+      0x0, 0x0, 0x0, 0x0, 0x0, 0x20, 0x0, 0x0, 0x14, 0x0, 0x0, 0x0,
+      // "x", file, inode 21 at 0x2000:0x4
+      0x04, 0x0, 0x01, 0x0, 0x02, 0x0, 0x0, 0x0, b'x',
+    ];
+
+    let entries = DirEntries::new(raw).collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries.len(), 3);
+
+    assert_eq!(entries[0].name, b"bin");
+    assert_eq!(entries[0].inode_type, InodeType::BasicDirectory);
+    assert_eq!(entries[0].inode_ref, InodeRef::new(0x20, 0x60));
+    assert_eq!(entries[0].inode_number, 10);
+    assert!(entries[0].is_dir());
+
+    assert_eq!(entries[1].name_lossy(), "sh");
+    assert_eq!(entries[1].inode_type, InodeType::BasicSymlink);
+    assert_eq!(entries[1].inode_number, 8);
+
+    assert_eq!(entries[2].name, b"x");
+    assert_eq!(entries[2].inode_ref.block(), 0x2000);
+    assert_eq!(entries[2].inode_ref.offset(), 4);
+    assert_eq!(entries[2].inode_number, 21);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_write_directory() -> Result<()> {
+    let entry = |name: &str, block: u32, inode_number: u32| DirEntry {
+      name: name.as_bytes().to_vec(),
+      inode_type: InodeType::BasicFile,
+      inode_ref: InodeRef::new(block, (inode_number % 1000) as u16 * 32),
+      inode_number,
+    };
+    let mut entries = vec![entry("a", 0, 1), entry("b", 0, 2), entry("c", 0x2000, 3)];
+    // too far from the base number of "c"
+    entries.push(entry("d", 0x2000, 3 + 40000));
+    entries.extend((0..300).map(|i| entry(&format!("e{:03}", i), 0x4000, 10 + i)));
+
+    let raw = write_directory(&entries);
+    // a header for "a" and "b", one for "c", one for "d" and two for the 300 "e"
+    let headers = 5;
+    let names: usize = entries.iter().map(|e| e.name.len()).sum();
+    assert_eq!(
+      raw.len(),
+      headers * DIRECTORY_HEADER_SIZE + entries.len() * DIRECTORY_ENTRY_SIZE + names
+    );
+
+    let parsed = DirEntries::new(raw).collect::<Result<Vec<_>>>()?;
+    assert_eq!(parsed.len(), entries.len());
+    for (parsed, entry) in parsed.iter().zip(&entries) {
+      assert_eq!(parsed.name, entry.name);
+      assert_eq!(parsed.inode_ref, entry.inode_ref);
+      assert_eq!(parsed.inode_number, entry.inode_number);
+    }
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_parse_truncated_directory() {
+    let raw = vec![
+      0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x1, 0x0, 0x0, 0x0, //
+      0x0, 0x0, 0x0, 0x0, 0x02, 0x0, 0x05, 0x0, b'a',
+    ];
+
+    let mut entries = DirEntries::new(raw);
+    assert!(entries.next().unwrap().is_err());
+    assert!(entries.next().is_none());
+  }
+}
@@ -0,0 +1,95 @@
+use super::*;
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{ErrorKind, Read, Result, SeekFrom};
+
+pub const EXPORT_ENTRY_SIZE: usize = 8;
+pub const NO_EXPORT_TABLE: u64 = 0xffff_ffff_ffff_ffff;
+
+/// The inode reference of every inode, the entry at `n - 1` belongs to inode number `n`.
+pub type ExportTab = Vec<InodeRef>;
+
+/// read the export table, `None` if the archive is not exportable.
+/// 1. Get start of the index from the `Superblock`;
+/// 2. Calculate table size via `inode_count*EXPORT_ENTRY_SIZE`;
+/// 3. Read the indexs, one per metablock of the table, 8 bytes each(u64);
+/// 4. Read the table, one inode reference per inode, 8 bytes each(u64).
+pub fn read_export_table(r: &mut dyn SqsIoRead, sb: &Superblock) -> Result<Option<ExportTab>> {
+  if !sb.flags.exportable() || sb.export_table_start == NO_EXPORT_TABLE {
+    return Ok(None);
+  }
+  let count = sb.inode_count as usize;
+  if count == 0 {
+    return Ok(Some(vec![]));
+  }
+  let table_size = EXPORT_ENTRY_SIZE * count;
+  let blocks = (table_size - 1) / METADATA_BLOCK_SIZE + 1;
+
+  // don't trust the inode count for preallocation, it comes from the image
+  let mut buf = vec![];
+  r.seek(SeekFrom::Start(sb.export_table_start))?;
+  r.take(blocks as u64 * 8).read_to_end(&mut buf)?;
+  if buf.len() < blocks * 8 {
+    return Err(std::io::Error::new(
+      ErrorKind::UnexpectedEof,
+      format!("export table needs {} block locations", blocks),
+    ));
+  }
+
+  let mut data: Vec<u8> = vec![];
+  for location in buf.chunks(8) {
+    let location = LittleEndian::read_u64(location);
+    let (uncompressed, _) = read_meta_block(r, sb.compressor, location)?;
+    trace!(
+      "[export tab] location={}, uncompressed.size={}",
+      location,
+      uncompressed.len()
+    );
+    data.extend(uncompressed);
+  }
+  if data.len() < table_size {
+    return Err(invalid_error!(format!(
+      "export table has {} bytes, {} inodes need {}",
+      data.len(),
+      count,
+      table_size
+    )));
+  }
+
+  Ok(Some(
+    data
+      .chunks(EXPORT_ENTRY_SIZE)
+      .take(count)
+      .map(|entry| InodeRef(LittleEndian::read_u64(entry)))
+      .collect(),
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::tests::*;
+  use crate::*;
+  use std::io::{Cursor, Result};
+
+  #[test]
+  fn test_read_export_table() -> Result<()> {
+    let mut cursor = Cursor::new(build_test_image());
+    let mut sb = Superblock::new();
+    sb.load(&mut cursor)?;
+
+    let table = read_export_table(&mut cursor, &sb)?.unwrap();
+    assert_eq!(table.len(), 6);
+    for (idx, inode_ref) in table.iter().enumerate() {
+      let inode = get_inode(&mut cursor, &sb, *inode_ref)?;
+      assert_eq!(inode.header().inode_number as usize, idx + 1);
+    }
+
+    sb.flags.remove(Flags::EXPORTABLE);
+    assert!(read_export_table(&mut cursor, &sb)?.is_none());
+
+    sb.flags.insert(Flags::EXPORTABLE);
+    sb.inode_count = u32::MAX;
+    assert!(read_export_table(&mut cursor, &sb).is_err());
+
+    Ok(())
+  }
+}
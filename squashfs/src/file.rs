@@ -0,0 +1,320 @@
+use super::*;
+use std::io::{self, ErrorKind, Read, Result, Seek, SeekFrom};
+
+/// Set in the on-disk size of a data block if the block is stored uncompressed.
+pub const UNCOMPRESSED_BLOCK_FLAG: u32 = 0x0100_0000;
+
+/// Returns the on-disk size of a data block and whether it is compressed.
+pub fn get_block_size(block_size: u32) -> (u32, bool) {
+  (
+    block_size & !UNCOMPRESSED_BLOCK_FLAG,
+    block_size & UNCOMPRESSED_BLOCK_FLAG != UNCOMPRESSED_BLOCK_FLAG,
+  )
+}
+
+/// Regular file inodes which point to data blocks and a fragment.
+pub trait FileInode {
+  /// The offset from the start of the archive where the data blocks are stored
+  fn blocks_start(&self) -> u64;
+
+  /// The (uncompressed) size of the file
+  fn file_size(&self) -> u64;
+
+  /// The index of the fragment entry, `NO_FRAGMENT` if the file has no tail end in a fragment
+  fn fragment_block_idx(&self) -> u32;
+
+  /// The (uncompressed) offset of the tail end within the fragment block
+  fn fragment_offset(&self) -> u32;
+
+  /// The on-disk size of each data block
+  fn block_sizes(&self) -> &[u32];
+}
+
+impl FileInode for BasicFile {
+  fn blocks_start(&self) -> u64 {
+    self.block_idx as u64
+  }
+
+  fn file_size(&self) -> u64 {
+    self.size as u64
+  }
+
+  fn fragment_block_idx(&self) -> u32 {
+    self.fragment_block_idx
+  }
+
+  fn fragment_offset(&self) -> u32 {
+    self.offset
+  }
+
+  fn block_sizes(&self) -> &[u32] {
+    &self.block_sizes
+  }
+}
+
+impl FileInode for ExtendedFile {
+  fn blocks_start(&self) -> u64 {
+    self.block_idx
+  }
+
+  fn file_size(&self) -> u64 {
+    self.size
+  }
+
+  fn fragment_block_idx(&self) -> u32 {
+    self.fragment_block_idx
+  }
+
+  fn fragment_offset(&self) -> u32 {
+    self.offset
+  }
+
+  fn block_sizes(&self) -> &[u32] {
+    &self.block_sizes
+  }
+}
+
+/// Reads the content of a regular file, decompressing one block at a time.
+pub struct FileReader<'a> {
+  r: &'a mut dyn SqsIoRead,
+  compressor: compress::Algorithm,
+  block_size: u32,
+  uncompressed_data: bool,
+  uncompressed_fragments: bool,
+  size: u64,
+  block_sizes: Vec<u32>,
+  /// the location of each data block in the archive
+  block_starts: Vec<u64>,
+  fragment: Option<FragmentEntry>,
+  fragment_offset: u32,
+  pos: u64,
+  /// index and content of the last decompressed block
+  cached: Option<(usize, Vec<u8>)>,
+}
+
+impl<'a> FileReader<'a> {
+  /// `fragment` is the entry at `file.fragment_block_idx()`, if the file has one.
+  pub fn new(
+    r: &'a mut dyn SqsIoRead,
+    sb: &Superblock,
+    file: &dyn FileInode,
+    fragment: Option<FragmentEntry>,
+  ) -> Result<Self> {
+    if file.fragment_block_idx() != NO_FRAGMENT && fragment.is_none() {
+      return Err(invalid_error!(format!(
+        "missing fragment entry {}",
+        file.fragment_block_idx()
+      )));
+    }
+
+    let mut block_starts = Vec::with_capacity(file.block_sizes().len());
+    let mut location = file.blocks_start();
+    for size in file.block_sizes() {
+      block_starts.push(location);
+      location += get_block_size(*size).0 as u64;
+    }
+
+    Ok(FileReader {
+      r,
+      compressor: sb.compressor,
+      block_size: sb.block_size,
+      uncompressed_data: sb.flags.uncompressed_data(),
+      uncompressed_fragments: sb.flags.uncompressed_fragments(),
+      size: file.file_size(),
+      block_sizes: file.block_sizes().to_vec(),
+      block_starts,
+      fragment: if file.fragment_block_idx() == NO_FRAGMENT {
+        None
+      } else {
+        fragment
+      },
+      fragment_offset: file.fragment_offset(),
+      pos: 0,
+      cached: None,
+    })
+  }
+
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  /// uncompressed size of the block at `idx`.
+  fn expected_size(&self, idx: usize) -> usize {
+    let start = idx as u64 * self.block_size as u64;
+    (self.size - start).min(self.block_size as u64) as usize
+  }
+
+  fn read_raw(&mut self, location: u64, size: u32) -> Result<Vec<u8>> {
+    let mut raw = vec![0u8; size as usize];
+    self.r.seek(SeekFrom::Start(location))?;
+    self.r.read_exact(&mut raw)?;
+    Ok(raw)
+  }
+
+  fn decompress(&self, raw: Vec<u8>, compressed: bool) -> Result<Vec<u8>> {
+    if !compressed {
+      return Ok(raw);
+    }
+    let mut output = vec![0u8; self.block_size as usize];
+    let size = compress::decompress(&raw, &mut output, self.compressor)?;
+    output.truncate(size);
+    Ok(output)
+  }
+
+  /// read and decompress the data block or the fragment tail at `idx`.
+  fn load_block(&mut self, idx: usize) -> Result<Vec<u8>> {
+    let expected = self.expected_size(idx);
+
+    let data = if idx < self.block_sizes.len() {
+      let (size, compressed) = get_block_size(self.block_sizes[idx]);
+      if size == 0 {
+        // sparse block
+        return Ok(vec![0u8; expected]);
+      }
+      let raw = self.read_raw(self.block_starts[idx], size)?;
+      let data = self.decompress(raw, compressed && !self.uncompressed_data)?;
+      trace!(
+        "[FileReader] block={}, size={}, compressed={}, data={}",
+        idx,
+        size,
+        compressed,
+        data.len()
+      );
+      data
+    } else {
+      let fragment = self
+        .fragment
+        .clone()
+        .ok_or_else(|| invalid_error!(format!("block {} out of file", idx)))?;
+      let raw = self.read_raw(fragment.start, fragment.size)?;
+      let block = self.decompress(raw, fragment.compressed && !self.uncompressed_fragments)?;
+      let start = self.fragment_offset as usize;
+      if block.len() < start + expected {
+        return Err(invalid_error!(format!(
+          "fragment block has {} bytes, tail end needs {}..{}",
+          block.len(),
+          start,
+          start + expected
+        )));
+      }
+      block[start..start + expected].to_vec()
+    };
+
+    if data.len() < expected {
+      return Err(invalid_error!(format!(
+        "data block {} has {} bytes, expected {}",
+        idx,
+        data.len(),
+        expected
+      )));
+    }
+
+    Ok(data)
+  }
+}
+
+impl Read for FileReader<'_> {
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+    if self.pos >= self.size || buf.is_empty() {
+      return Ok(0);
+    }
+
+    let idx = (self.pos / self.block_size as u64) as usize;
+    let offset = (self.pos % self.block_size as u64) as usize;
+    let block = match self.cached.take() {
+      Some((cached, block)) if cached == idx => block,
+      _ => self.load_block(idx)?,
+    };
+
+    let expected = self.expected_size(idx);
+    let n = buf.len().min(expected - offset);
+    buf[..n].copy_from_slice(&block[offset..offset + n]);
+    self.pos += n as u64;
+    self.cached = Some((idx, block));
+
+    Ok(n)
+  }
+}
+
+impl Seek for FileReader<'_> {
+  fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+    let pos = match pos {
+      SeekFrom::Start(pos) => Some(pos),
+      SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+      SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+    };
+    self.pos = pos.ok_or_else(|| {
+      io::Error::new(
+        ErrorKind::InvalidInput,
+        "invalid seek to a negative or overflowing position",
+      )
+    })?;
+    Ok(self.pos)
+  }
+}
+
+#[cfg(all(test, feature = "gzip"))]
+mod tests {
+  use crate::*;
+  use flate2::write::ZlibEncoder;
+  use flate2::Compression;
+  use std::io::{Cursor, Read, Result, Seek, SeekFrom, Write};
+
+  #[test]
+  fn test_read_file() -> Result<()> {
+    let block_size = 4096;
+    let mut zlib = ZlibEncoder::new(vec![], Compression::default());
+    zlib.write_all(&[b'a'; 4096])?;
+    let compressed = zlib.finish()?;
+
+    // compressed block, uncompressed block, sparse block, fragment block
+    let mut image = compressed.clone();
+    image.extend(&[b'b'; 4096]);
+    let fragment_start = image.len() as u64;
+    image.extend(b"xxxtail");
+
+    let file = BasicFile {
+      block_idx: 0,
+      fragment_block_idx: 0,
+      offset: 3,
+      size: 3 * 4096 + 4,
+      block_sizes: vec![
+        compressed.len() as u32,
+        4096 | UNCOMPRESSED_BLOCK_FLAG,
+        0,
+      ],
+    };
+    let fragment = FragmentEntry {
+      start: fragment_start,
+      size: 7,
+      compressed: false,
+    };
+    let sb = Superblock {
+      block_size,
+      compressor: compress::Algorithm::Gzip,
+      ..Default::default()
+    };
+
+    let mut cursor = Cursor::new(image);
+    let mut reader = FileReader::new(&mut cursor, &sb, &file, Some(fragment))?;
+    let mut content = vec![];
+    reader.read_to_end(&mut content)?;
+
+    assert_eq!(content.len() as u64, reader.size());
+    assert!(content[..4096].iter().all(|b| *b == b'a'));
+    assert!(content[4096..8192].iter().all(|b| *b == b'b'));
+    assert!(content[8192..12288].iter().all(|b| *b == 0));
+    assert_eq!(&content[12288..], b"tail");
+
+    let mut buf = [0u8; 6];
+    reader.seek(SeekFrom::Start(4094))?;
+    reader.read_exact(&mut buf)?;
+    assert_eq!(&buf, b"aabbbb");
+    reader.seek(SeekFrom::End(-2))?;
+    assert_eq!(reader.read(&mut buf)?, 2);
+    assert_eq!(&buf[..2], b"il");
+    assert!(reader.seek(SeekFrom::Current(-100_000)).is_err());
+
+    Ok(())
+  }
+}
@@ -1,91 +1,92 @@
 use super::*;
 use byteorder::{ByteOrder, LittleEndian};
-use std::io::{Read, Result, SeekFrom};
-use std::mem;
+use std::io::{Result, SeekFrom};
 
-///
+/// Fragment table, one entry per fragment block.
 #[derive(Default, Debug)]
 pub struct FragmentsTab {
   pub entries: Vec<FragmentEntry>,
 }
 
-#[derive(Default, Debug)]
+#[derive(Clone, Default, Debug)]
 pub struct FragmentEntry {
+  /// The offset within the archive where the fragment block starts
   pub start: u64,
+  /// The on-disk size of the fragment block, without the uncompressed flag
   pub size: u32,
   pub compressed: bool,
 }
 
-#[repr(C)]
-#[derive(Default, Debug)]
-struct FragmentEntryInternal {
-  start: u64,
-  size: u32,
-  _padding: u32,
-}
-
-impl_converter!(FragmentEntryInternal);
-
-pub const FRAGMENT_SIZE: usize = mem::size_of::<FragmentEntryInternal>();
-pub const UNCOMPRESSED_FRAGMENT_FLAG: u32 = 0x1000_000;
+/// On disk: start u64, size u32 and an unused u32.
+pub const FRAGMENT_SIZE: usize = 16;
+pub const UNCOMPRESSED_FRAGMENT_FLAG: u32 = 0x0100_0000;
+/// The `fragment_block_idx` of a file inode which does not end with a fragment.
+pub const NO_FRAGMENT: u32 = 0xffff_ffff;
 
-pub fn read_fragment_table(r: &mut SqsIoReader, sb: Superblock) -> Result<FragmentsTab> {
+pub fn read_fragment_table(r: &mut dyn SqsIoRead, sb: &Superblock) -> Result<FragmentsTab> {
   let mut blocks = sb.fragment_entry_count / 512;
-  if sb.fragment_entry_count % 512 > 0 {
+  if !sb.fragment_entry_count.is_multiple_of(512) {
     blocks += 1;
   }
 
-  let mut tab = FragmentsTab::default();
+  // the indexes are stored uncompressed, one per metablock of the table, 8 bytes each.
+  let mut buf = vec![0u8; blocks as usize * 8];
   r.seek(SeekFrom::Start(sb.fragment_table_start))?;
-  let mut buf = [0u8; 8];
-  while blocks > 0 {
-    r.read_exact(&mut buf)?;
-
-    trace!("block={} buf={:?}", blocks, buf);
+  r.read_exact(&mut buf)?;
 
-    let offset = LittleEndian::read_u64(&buf);
+  let mut tab = FragmentsTab::default();
+  for (block, location) in buf.chunks(8).enumerate() {
+    let offset = LittleEndian::read_u64(location);
+    trace!("block={} offset={}", block, offset);
     let (metadata, _) = read_meta_block(r, sb.compressor, offset)?;
 
-    let total = metadata.len() / FRAGMENT_SIZE;
-    let mut idx = 0;
-    while idx < total {
-      let start = idx * FRAGMENT_SIZE;
-      let end = (idx + 1) * FRAGMENT_SIZE;
-      let fragment = parse_fragment(&mut &metadata[start..end])?;
+    for raw in metadata.chunks_exact(FRAGMENT_SIZE) {
+      let fragment = parse_fragment(raw)?;
       tab.entries.push(fragment);
-      idx = idx + 1;
     }
 
     debug!(
       "[read_fragment_table] total={}, parsed={}",
-      total,
+      metadata.len() / FRAGMENT_SIZE,
       tab.entries.len()
     );
 
     trace!("[read_fragment_table] parsed.fragment={:?}", tab.entries);
-
-    blocks -= 1;
   }
+  tab.entries.truncate(sb.fragment_entry_count as usize);
 
   Ok(tab)
 }
 
+impl FragmentEntry {
+  /// Encode the on-disk entry, the unused field is zero.
+  pub fn to_bytes(&self) -> [u8; FRAGMENT_SIZE] {
+    let mut data = [0u8; FRAGMENT_SIZE];
+    let mut size = self.size;
+    if !self.compressed {
+      size |= UNCOMPRESSED_FRAGMENT_FLAG;
+    }
+    LittleEndian::write_u64(&mut data[0..8], self.start);
+    LittleEndian::write_u32(&mut data[8..12], size);
+    data
+  }
+}
+
 fn is_uncompressed_fragment(s: u32) -> bool {
   s & UNCOMPRESSED_FRAGMENT_FLAG == UNCOMPRESSED_FRAGMENT_FLAG
 }
 
-fn parse_fragment(metadata: &mut &[u8]) -> Result<FragmentEntry> {
+pub fn parse_fragment(metadata: &[u8]) -> Result<FragmentEntry> {
   if metadata.len() != FRAGMENT_SIZE {
     return Err(invalid_error!("invalid fragment data, should has 16 bytes"));
   }
-  trace!("[parse_fragment] bytes={:x?}, {:x?}", metadata, 1 << 24);
-  let mut internal = FragmentEntryInternal::default();
-  metadata.read_exact(&mut internal.as_mut())?;
+  trace!("[parse_fragment] bytes={:x?}", metadata);
+  let size = LittleEndian::read_u32(&metadata[8..12]);
 
   Ok(FragmentEntry {
-    start: internal.start,
-    size: internal.size,
-    compressed: !is_uncompressed_fragment(internal.size),
+    start: LittleEndian::read_u64(&metadata[0..8]),
+    size: size & !UNCOMPRESSED_FRAGMENT_FLAG,
+    compressed: !is_uncompressed_fragment(size),
   })
 }
 
@@ -100,7 +101,31 @@ mod tests {
   fn test_read_fragment_table() -> Result<()> {
     prepare_tests()?;
     let (mut reader, sb) = prepare_tests()?;
-    read_fragment_table(&mut reader, sb)?;
+    read_fragment_table(&mut reader, &sb)?;
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_parse_fragment() -> Result<()> {
+    let raw = [
+      0x60, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x10, 0x0, 0x0, 0x1, 0xff, 0xff, 0xff, 0xff,
+    ];
+    let fragment = parse_fragment(&raw)?;
+    assert_eq!(fragment.start, 0x60);
+    assert_eq!(fragment.size, 0x10);
+    assert!(!fragment.compressed);
+    assert_eq!(fragment.to_bytes()[..12], raw[..12]);
+
+    let raw = [
+      0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, 0x34, 0x12, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+    ];
+    let fragment = parse_fragment(&raw)?;
+    assert_eq!(fragment.start, 0x0102_0304_0506_0708);
+    assert_eq!(fragment.size, 0x1234);
+    assert!(fragment.compressed);
+    assert_eq!(fragment.to_bytes(), raw);
+    assert!(parse_fragment(&raw[..12]).is_err());
 
     Ok(())
   }
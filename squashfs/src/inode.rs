@@ -1,9 +1,10 @@
 use super::*;
-use std::io::Result;
-use std::mem;
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
+use std::convert::TryFrom;
+use std::io::{ErrorKind, Read, Result};
 
 #[repr(u16)]
-#[derive(Clone, SmartDefault, Debug)]
+#[derive(Clone, Copy, SmartDefault, Debug, PartialEq)]
 pub enum InodeType {
   #[default]
   BasicDirectory = 1,
@@ -22,7 +23,45 @@ pub enum InodeType {
   ExtendedSocket,
 }
 
+impl TryFrom<u16> for InodeType {
+  type Error = std::io::Error;
+
+  fn try_from(v: u16) -> Result<Self> {
+    Ok(match v {
+      1 => InodeType::BasicDirectory,
+      2 => InodeType::BasicFile,
+      3 => InodeType::BasicSymlink,
+      4 => InodeType::BasicBlockDevice,
+      5 => InodeType::BasicCharDevice,
+      6 => InodeType::BasicFifo,
+      7 => InodeType::BasicSocket,
+      8 => InodeType::ExtendedDirectory,
+      9 => InodeType::ExtendedFile,
+      10 => InodeType::ExtendedSymlink,
+      11 => InodeType::ExtendedBlockDevice,
+      12 => InodeType::ExtendedCharDevice,
+      13 => InodeType::ExtendedFifo,
+      14 => InodeType::ExtendedSocket,
+      _ => return Err(invalid_error!(format!("unknown inode type {}", v))),
+    })
+  }
+}
+
 impl InodeType {
+  /// The basic type of an extended type, used in directory entries.
+  pub fn basic(&self) -> InodeType {
+    match self {
+      InodeType::ExtendedDirectory => InodeType::BasicDirectory,
+      InodeType::ExtendedFile => InodeType::BasicFile,
+      InodeType::ExtendedSymlink => InodeType::BasicSymlink,
+      InodeType::ExtendedBlockDevice => InodeType::BasicBlockDevice,
+      InodeType::ExtendedCharDevice => InodeType::BasicCharDevice,
+      InodeType::ExtendedFifo => InodeType::BasicFifo,
+      InodeType::ExtendedSocket => InodeType::BasicSocket,
+      basic => *basic,
+    }
+  }
+
   pub fn body_size(&self) -> usize {
     match self {
       InodeType::BasicDirectory => BASIC_DIRECTORY_BODY_SIZE,
@@ -44,8 +83,7 @@ impl InodeType {
   }
 }
 
-pub const BASIC_DIRECTORY_BODY_SIZE: usize = mem::size_of::<BasicDirectory>();
-#[repr(C)]
+pub const BASIC_DIRECTORY_BODY_SIZE: usize = 16;
 #[derive(Clone, Default, Debug)]
 pub struct BasicDirectory {
   /// The index of the block in the Directory Table where the directory entry information starts
@@ -63,12 +101,7 @@ pub struct BasicDirectory {
   /// The inode_number of the parent of this directory. If this is the root directory, this will be 1
   pub parent_inode: u32,
 }
-impl_converter!(BasicDirectory);
-
-impl InodeBody for BasicDirectory {}
-
-pub const BASIC_FILE_BODY_SIZE: usize = mem::size_of::<BasicFile>();
-#[repr(C)]
+pub const BASIC_FILE_BODY_SIZE: usize = 16;
 #[derive(Clone, Default, Debug)]
 pub struct BasicFile {
   /// The offset from the start of the archive where the data blocks are stored
@@ -82,13 +115,11 @@ pub struct BasicFile {
 
   /// The (uncompressed) size of this file
   pub size: u32,
-}
-impl_converter!(BasicFile);
-
-impl InodeBody for BasicFile {}
 
-pub const BASIC_SYMLINK_BODY_SIZE: usize = mem::size_of::<BasicSymlink>();
-#[repr(C)]
+  /// The on-disk size of each data block. Bit 24 is set if the block is stored uncompressed, a size of 0 means a sparse block of zeros
+  pub block_sizes: Vec<u32>,
+}
+pub const BASIC_SYMLINK_BODY_SIZE: usize = 8;
 #[derive(Clone, Default, Debug)]
 pub struct BasicSymlink {
   /// The number of hard links to this directory
@@ -96,13 +127,11 @@ pub struct BasicSymlink {
 
   /// The size in bytes of the `target_path` this symlink points to
   pub target_size: u32,
-}
-impl_converter!(BasicSymlink);
 
-impl InodeBody for BasicSymlink {}
-
-pub const BASIC_BLOCK_BODY_SIZE: usize = mem::size_of::<BasicBlockDevice>();
-#[repr(C)]
+  /// The target path this symlink points to, not nul terminated
+  pub target_path: Vec<u8>,
+}
+pub const BASIC_BLOCK_BODY_SIZE: usize = 8;
 #[derive(Clone, Default, Debug)]
 pub struct BasicBlockDevice {
   /// The number of hard links to this directory
@@ -112,12 +141,12 @@ pub struct BasicBlockDevice {
   pub device: u32,
 }
 
-impl_converter!(BasicBlockDevice);
-
-impl InodeBody for BasicBlockDevice {}
+/// Encode a device number the way `BasicBlockDevice::device` stores it.
+pub fn device_number(major: u32, minor: u32) -> u32 {
+  (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & 0xfff00) << 12)
+}
 
-pub const BASIC_CHAR_BODY_SIZE: usize = mem::size_of::<BasicCharDevice>();
-#[repr(C)]
+pub const BASIC_CHAR_BODY_SIZE: usize = 8;
 #[derive(Clone, Default, Debug)]
 pub struct BasicCharDevice {
   /// The number of hard links to this directory
@@ -127,36 +156,21 @@ pub struct BasicCharDevice {
   pub device: u32,
 }
 
-impl_converter!(BasicCharDevice);
-
-impl InodeBody for BasicCharDevice {}
-
-pub const BASIC_FIFO_BODY_SIZE: usize = mem::size_of::<BasicFifo>();
-#[repr(C)]
+pub const BASIC_FIFO_BODY_SIZE: usize = 4;
 #[derive(Clone, Default, Debug)]
 pub struct BasicFifo {
   /// The number of hard links to this directory
   pub nlink: u32,
 }
 
-impl_converter!(BasicFifo);
-
-impl InodeBody for BasicFifo {}
-
-pub const BASIC_SOCKET_BODY_SIZE: usize = mem::size_of::<BasicSocket>();
-#[repr(C)]
+pub const BASIC_SOCKET_BODY_SIZE: usize = 4;
 #[derive(Clone, Default, Debug)]
 pub struct BasicSocket {
   /// The number of hard links to this directory
   pub nlink: u32,
 }
 
-impl_converter!(BasicSocket);
-
-impl InodeBody for BasicSocket {}
-
-pub const EXTENDED_DIRECTORY_BODY_SIZE: usize = mem::size_of::<ExtendedDirectory>();
-#[repr(C)]
+pub const EXTENDED_DIRECTORY_BODY_SIZE: usize = 24;
 #[derive(Clone, Default, Debug)]
 pub struct ExtendedDirectory {
   /// The number of hard links to this directory
@@ -179,14 +193,24 @@ pub struct ExtendedDirectory {
 
   /// An index into the xattr lookup table. Set to 0xFFFFFFFF if the inode has no extended attributes
   pub xattr_idx: u32,
+
+  /// Index entries to speed up the lookup of names in large directories
+  pub index: Vec<DirectoryIndex>,
 }
 
-impl_converter!(ExtendedDirectory);
+#[derive(Clone, Default, Debug)]
+pub struct DirectoryIndex {
+  /// The byte offset of the directory header from the start of the listing, as if it was uncompressed and contiguous
+  pub index: u32,
 
-impl InodeBody for ExtendedDirectory {}
+  /// The offset of the metadata block holding the header, relative to `Superblock::directory_table_start`
+  pub start: u32,
 
-pub const EXTENDED_FILE_BODY_SIZE: usize = mem::size_of::<ExtendedFile>();
-#[repr(C)]
+  /// The name of the first entry after the header, not nul terminated
+  pub name: Vec<u8>,
+}
+
+pub const EXTENDED_FILE_BODY_SIZE: usize = 40;
 #[derive(Clone, Default, Debug)]
 pub struct ExtendedFile {
   /// The offset from the start of the archive where the data blocks are stored
@@ -209,14 +233,12 @@ pub struct ExtendedFile {
 
   /// An index into the xattr lookup table. Set to 0xFFFFFFFF if the inode has no extended attributes
   pub xattr_idx: u32,
-}
 
-impl_converter!(ExtendedFile);
-
-impl InodeBody for ExtendedFile {}
+  /// The on-disk size of each data block. Bit 24 is set if the block is stored uncompressed, a size of 0 means a sparse block of zeros
+  pub block_sizes: Vec<u32>,
+}
 
-pub const EXTENDED_SYMLINK_BODY_SIZE: usize = mem::size_of::<ExtendedSymlink>();
-#[repr(C)]
+pub const EXTENDED_SYMLINK_BODY_SIZE: usize = 12;
 #[derive(Clone, Default, Debug)]
 pub struct ExtendedSymlink {
   /// The number of hard links to this directory
@@ -225,16 +247,14 @@ pub struct ExtendedSymlink {
   /// The size in bytes of the `target_path` this symlink points to
   pub target_size: u32,
 
+  /// The target path this symlink points to, not nul terminated. On disk it is stored before `xattr_idx`
+  pub target_path: Vec<u8>,
+
   /// An index into the xattr lookup table. Set to 0xFFFFFFFF if the inode has no extended attributes
   pub xattr_idx: u32,
 }
 
-impl_converter!(ExtendedSymlink);
-
-impl InodeBody for ExtendedSymlink {}
-
-pub const EXTENDED_BLOCK_BODY_SIZE: usize = mem::size_of::<ExtendedBlock>();
-#[repr(C)]
+pub const EXTENDED_BLOCK_BODY_SIZE: usize = 12;
 #[derive(Clone, Default, Debug)]
 pub struct ExtendedBlock {
   /// The number of hard links to this directory
@@ -247,12 +267,7 @@ pub struct ExtendedBlock {
   pub xattr_idx: u32,
 }
 
-impl_converter!(ExtendedBlock);
-
-impl InodeBody for ExtendedBlock {}
-
-pub const EXTENDED_CHAR_BODY_SIZE: usize = mem::size_of::<ExtendedChar>();
-#[repr(C)]
+pub const EXTENDED_CHAR_BODY_SIZE: usize = 12;
 #[derive(Clone, Default, Debug)]
 pub struct ExtendedChar {
   /// The number of hard links to this directory
@@ -265,12 +280,7 @@ pub struct ExtendedChar {
   pub xattr_idx: u32,
 }
 
-impl_converter!(ExtendedChar);
-
-impl InodeBody for ExtendedChar {}
-
-pub const EXTENDED_FIFO_BODY_SIZE: usize = mem::size_of::<ExtendedFifo>();
-#[repr(C)]
+pub const EXTENDED_FIFO_BODY_SIZE: usize = 8;
 #[derive(Clone, Default, Debug)]
 pub struct ExtendedFifo {
   /// The number of hard links to this directory
@@ -280,12 +290,7 @@ pub struct ExtendedFifo {
   pub xattr_idx: u32,
 }
 
-impl_converter!(ExtendedFifo);
-
-impl InodeBody for ExtendedFifo {}
-
-pub const EXTENDED_SOCKET_BODY_SIZE: usize = mem::size_of::<ExtendedSocket>();
-#[repr(C)]
+pub const EXTENDED_SOCKET_BODY_SIZE: usize = 8;
 #[derive(Clone, Default, Debug)]
 pub struct ExtendedSocket {
   /// The number of hard links to this directory
@@ -295,31 +300,6 @@ pub struct ExtendedSocket {
   pub xattr_idx: u32,
 }
 
-impl_converter!(ExtendedSocket);
-
-impl InodeBody for ExtendedSocket {}
-
-///
-/// Inodes
-///
-#[derive(Clone, Default)]
-pub struct InodeTab {
-  pub(crate) data: Vec<InodeHeader>,
-}
-impl_converter!(InodeTab);
-
-impl InodeTab {
-  pub fn new() -> Self {
-    Self::default()
-  }
-
-  pub fn load(&mut self, r: &mut SqsIoReader) -> Result<()> {
-    r.read_exact(self.as_mut())?;
-    Ok(())
-  }
-}
-
-#[repr(C)]
 #[derive(Clone, Default, Debug)]
 pub struct InodeHeader {
   /// The type of item described by the inode which follows this header.
@@ -341,180 +321,642 @@ pub struct InodeHeader {
   pub inode_number: u32,
 }
 
-trait InodeBody {}
+/// A parsed inode: the common header plus the type specific body.
+#[derive(Clone, Debug)]
+pub enum Inode {
+  BasicDirectory(InodeHeader, BasicDirectory),
+  BasicFile(InodeHeader, BasicFile),
+  BasicSymlink(InodeHeader, BasicSymlink),
+  BasicBlockDevice(InodeHeader, BasicBlockDevice),
+  BasicCharDevice(InodeHeader, BasicCharDevice),
+  BasicFifo(InodeHeader, BasicFifo),
+  BasicSocket(InodeHeader, BasicSocket),
+  ExtendedDirectory(InodeHeader, ExtendedDirectory),
+  ExtendedFile(InodeHeader, ExtendedFile),
+  ExtendedSymlink(InodeHeader, ExtendedSymlink),
+  ExtendedBlockDevice(InodeHeader, ExtendedBlock),
+  ExtendedCharDevice(InodeHeader, ExtendedChar),
+  ExtendedFifo(InodeHeader, ExtendedFifo),
+  ExtendedSocket(InodeHeader, ExtendedSocket),
+}
+
+impl Inode {
+  pub fn header(&self) -> &InodeHeader {
+    match self {
+      Inode::BasicDirectory(h, _)
+      | Inode::BasicFile(h, _)
+      | Inode::BasicSymlink(h, _)
+      | Inode::BasicBlockDevice(h, _)
+      | Inode::BasicCharDevice(h, _)
+      | Inode::BasicFifo(h, _)
+      | Inode::BasicSocket(h, _)
+      | Inode::ExtendedDirectory(h, _)
+      | Inode::ExtendedFile(h, _)
+      | Inode::ExtendedSymlink(h, _)
+      | Inode::ExtendedBlockDevice(h, _)
+      | Inode::ExtendedCharDevice(h, _)
+      | Inode::ExtendedFifo(h, _)
+      | Inode::ExtendedSocket(h, _) => h,
+    }
+  }
+
+  pub fn inode_type(&self) -> InodeType {
+    self.header().inode_type
+  }
+
+  pub fn is_dir(&self) -> bool {
+    self.as_directory().is_some()
+  }
+
+  /// Returns the target path of a basic or extended symlink inode.
+  pub fn symlink_target(&self) -> Option<&[u8]> {
+    match self {
+      Inode::BasicSymlink(_, body) => Some(&body.target_path),
+      Inode::ExtendedSymlink(_, body) => Some(&body.target_path),
+      _ => None,
+    }
+  }
 
-impl_converter!(InodeHeader);
+  /// Returns the index into the xattr id table, only extended inodes have xattrs.
+  pub fn xattr_idx(&self) -> Option<u32> {
+    let idx = match self {
+      Inode::ExtendedDirectory(_, body) => body.xattr_idx,
+      Inode::ExtendedFile(_, body) => body.xattr_idx,
+      Inode::ExtendedSymlink(_, body) => body.xattr_idx,
+      Inode::ExtendedBlockDevice(_, body) => body.xattr_idx,
+      Inode::ExtendedCharDevice(_, body) => body.xattr_idx,
+      Inode::ExtendedFifo(_, body) => body.xattr_idx,
+      Inode::ExtendedSocket(_, body) => body.xattr_idx,
+      _ => return None,
+    };
+    if idx == NO_XATTR_INODE_FLAG {
+      None
+    } else {
+      Some(idx)
+    }
+  }
+
+  /// Returns the file body of a basic or extended file inode.
+  pub fn as_file(&self) -> Option<&dyn FileInode> {
+    match self {
+      Inode::BasicFile(_, body) => Some(body),
+      Inode::ExtendedFile(_, body) => Some(body),
+      _ => None,
+    }
+  }
+
+  /// Returns the directory body of a basic or extended directory inode.
+  pub fn as_directory(&self) -> Option<&dyn DirectoryInode> {
+    match self {
+      Inode::BasicDirectory(_, body) => Some(body),
+      Inode::ExtendedDirectory(_, body) => Some(body),
+      _ => None,
+    }
+  }
+}
 
-pub fn get_inode(
-  r: &mut SqsIoReader,
-  sb: Superblock,
-  block: u32,
-  offset: u32,
-  inode_type: InodeType,
-) -> Result<()> {
-  // get the block
-  let size = inode_type.body_size();
+/// read an inode from the inode table.
+/// 1. Read the metadata at `inode_ref`, relative to `Superblock::inode_table_start`;
+/// 2. Parse the header to get the inode type;
+/// 3. Parse the body of that type.
+pub fn get_inode(r: &mut dyn SqsIoRead, sb: &Superblock, inode_ref: InodeRef) -> Result<Inode> {
+  let block = inode_ref.block();
+  let offset = inode_ref.offset() as u32;
 
   // read inode header
-  let uncompressed = read_metadata(r, sb.compressor, sb.inode_table_start, block, offset, size)?;
-  trace!("[get_inode]size={}, uncompressed={:x?}", size, uncompressed);
-  let _inode_header = parse_inode_header(uncompressed[0..INODE_HEADER_SIZE].to_vec())?;
-
-  // read inode body
-  parse_inode_body(
-    (&uncompressed[INODE_HEADER_SIZE..]).to_vec(),
-    sb.block_size,
-    inode_type,
+  let mut uncompressed = read_metadata(
+    r,
+    sb.compressor,
+    sb.inode_table_start,
+    block,
+    offset,
+    INODE_HEADER_SIZE,
   )?;
+  let header = parse_inode_header(&uncompressed)?;
+
+  // the body and its variable length trailer may continue in the next metadata blocks,
+  // read more until the whole inode is available.
+  let mut size = INODE_HEADER_SIZE + header.inode_type.body_size();
+  loop {
+    if uncompressed.len() >= size {
+      trace!("[get_inode]size={}, uncompressed={:x?}", size, uncompressed);
+      match parse_inode_body(header.clone(), &uncompressed[INODE_HEADER_SIZE..], sb.block_size) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => size = uncompressed.len() * 2,
+        result => return result,
+      }
+    }
+    uncompressed = read_metadata(r, sb.compressor, sb.inode_table_start, block, offset, size)?;
+  }
+}
+
+pub const INODE_HEADER_SIZE: usize = 16;
 
-  Ok(())
+impl InodeHeader {
+  pub fn to_bytes(&self) -> [u8; INODE_HEADER_SIZE] {
+    let mut data = [0u8; INODE_HEADER_SIZE];
+    LittleEndian::write_u16(&mut data[0..2], self.inode_type as u16);
+    LittleEndian::write_u16(&mut data[2..4], self.permissions);
+    LittleEndian::write_u16(&mut data[4..6], self.uid_idx);
+    LittleEndian::write_u16(&mut data[6..8], self.gid_idx);
+    LittleEndian::write_u32(&mut data[8..12], self.modified_time);
+    LittleEndian::write_u32(&mut data[12..16], self.inode_number);
+    data
+  }
 }
 
-const INODE_HEADER_SIZE: usize = 16;
-fn parse_inode_header(data: Vec<u8>) -> Result<InodeHeader> {
+impl Inode {
+  /// Encode the inode as stored in the inode table, the counts of the variable
+  /// length trailers are taken from the trailers.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut data = self.header().to_bytes().to_vec();
+    let mut put = |v: &[u8]| data.extend_from_slice(v);
+    match self {
+      Inode::BasicDirectory(_, body) => {
+        put(&body.block_idx.to_le_bytes());
+        put(&body.nlink.to_le_bytes());
+        put(&body.size.to_le_bytes());
+        put(&body.offset.to_le_bytes());
+        put(&body.parent_inode.to_le_bytes());
+      }
+      Inode::BasicFile(_, body) => {
+        put(&body.block_idx.to_le_bytes());
+        put(&body.fragment_block_idx.to_le_bytes());
+        put(&body.offset.to_le_bytes());
+        put(&body.size.to_le_bytes());
+        body.block_sizes.iter().for_each(|size| put(&size.to_le_bytes()));
+      }
+      Inode::BasicSymlink(_, body) => {
+        put(&body.nlink.to_le_bytes());
+        put(&(body.target_path.len() as u32).to_le_bytes());
+        put(&body.target_path);
+      }
+      Inode::BasicBlockDevice(_, body) => {
+        put(&body.nlink.to_le_bytes());
+        put(&body.device.to_le_bytes());
+      }
+      Inode::BasicCharDevice(_, body) => {
+        put(&body.nlink.to_le_bytes());
+        put(&body.device.to_le_bytes());
+      }
+      Inode::BasicFifo(_, body) => put(&body.nlink.to_le_bytes()),
+      Inode::BasicSocket(_, body) => put(&body.nlink.to_le_bytes()),
+      Inode::ExtendedDirectory(_, body) => {
+        put(&body.nlink.to_le_bytes());
+        put(&body.size.to_le_bytes());
+        put(&body.block_idx.to_le_bytes());
+        put(&body.parent_inode.to_le_bytes());
+        put(&(body.index.len() as u16).to_le_bytes());
+        put(&body.offset.to_le_bytes());
+        put(&body.xattr_idx.to_le_bytes());
+        for index in &body.index {
+          put(&index.index.to_le_bytes());
+          put(&index.start.to_le_bytes());
+          put(&(index.name.len() as u32 - 1).to_le_bytes());
+          put(&index.name);
+        }
+      }
+      Inode::ExtendedFile(_, body) => {
+        put(&body.block_idx.to_le_bytes());
+        put(&body.size.to_le_bytes());
+        put(&body.sparse.to_le_bytes());
+        put(&body.nlink.to_le_bytes());
+        put(&body.fragment_block_idx.to_le_bytes());
+        put(&body.offset.to_le_bytes());
+        put(&body.xattr_idx.to_le_bytes());
+        body.block_sizes.iter().for_each(|size| put(&size.to_le_bytes()));
+      }
+      Inode::ExtendedSymlink(_, body) => {
+        put(&body.nlink.to_le_bytes());
+        put(&(body.target_path.len() as u32).to_le_bytes());
+        put(&body.target_path);
+        put(&body.xattr_idx.to_le_bytes());
+      }
+      Inode::ExtendedBlockDevice(_, body) => {
+        put(&body.nlink.to_le_bytes());
+        put(&body.device.to_le_bytes());
+        put(&body.xattr_idx.to_le_bytes());
+      }
+      Inode::ExtendedCharDevice(_, body) => {
+        put(&body.nlink.to_le_bytes());
+        put(&body.device.to_le_bytes());
+        put(&body.xattr_idx.to_le_bytes());
+      }
+      Inode::ExtendedFifo(_, body) => {
+        put(&body.nlink.to_le_bytes());
+        put(&body.xattr_idx.to_le_bytes());
+      }
+      Inode::ExtendedSocket(_, body) => {
+        put(&body.nlink.to_le_bytes());
+        put(&body.xattr_idx.to_le_bytes());
+      }
+    }
+    data
+  }
+}
+
+pub fn parse_inode_header(data: &[u8]) -> Result<InodeHeader> {
   if data.len() < INODE_HEADER_SIZE {
     return Err(invalid_error!("input data must great than 15 bytes"));
   }
-  let mut data = &*data;
-  let mut header = InodeHeader::default();
-  data.read_exact(header.as_mut())?;
+  let header = InodeHeader {
+    inode_type: InodeType::try_from(LittleEndian::read_u16(&data[0..2]))?,
+    permissions: LittleEndian::read_u16(&data[2..4]),
+    uid_idx: LittleEndian::read_u16(&data[4..6]),
+    gid_idx: LittleEndian::read_u16(&data[6..8]),
+    modified_time: LittleEndian::read_u32(&data[8..12]),
+    inode_number: LittleEndian::read_u32(&data[12..16]),
+  };
 
   debug!("[parse_inode_header] header={:?}", header);
 
   Ok(header)
 }
 
-fn parse_inode_body(data: Vec<u8>, block_size: u32, itype: InodeType) -> Result<()> {
-  let _body: Box<dyn InodeBody> = match itype {
-    InodeType::BasicDirectory => parse_basic_directory(data)?,
-    InodeType::BasicFile => parse_basic_file(data, block_size)?,
-    InodeType::BasicSymlink => parse_basic_symlink()?,
-    InodeType::BasicBlockDevice | InodeType::BasicCharDevice => parse_basic_block_device()?,
-    InodeType::BasicFifo | InodeType::BasicSocket => parse_basic_ipc()?,
-
-    InodeType::ExtendedDirectory => parse_extened_directory()?,
-    InodeType::ExtendedFile => parse_extened_file()?,
-    InodeType::ExtendedSymlink => parse_extened_symlink()?,
-    InodeType::ExtendedBlockDevice | InodeType::ExtendedCharDevice => parse_extened_block_device()?,
-    InodeType::ExtendedFifo | InodeType::ExtendedSocket => parse_extened_ipc()?,
+pub fn parse_inode_body(header: InodeHeader, data: &[u8], block_size: u32) -> Result<Inode> {
+  let inode = match header.inode_type {
+    InodeType::BasicDirectory => Inode::BasicDirectory(header, parse_basic_directory(data)?),
+    InodeType::BasicFile => Inode::BasicFile(header, parse_basic_file(data, block_size)?),
+    InodeType::BasicSymlink => Inode::BasicSymlink(header, parse_basic_symlink(data)?),
+    InodeType::BasicBlockDevice => Inode::BasicBlockDevice(header, parse_basic_block_device(data)?),
+    InodeType::BasicCharDevice => Inode::BasicCharDevice(header, parse_basic_char_device(data)?),
+    InodeType::BasicFifo => Inode::BasicFifo(header, parse_basic_fifo(data)?),
+    InodeType::BasicSocket => Inode::BasicSocket(header, parse_basic_socket(data)?),
+
+    InodeType::ExtendedDirectory => Inode::ExtendedDirectory(header, parse_extened_directory(data)?),
+    InodeType::ExtendedFile => Inode::ExtendedFile(header, parse_extened_file(data, block_size)?),
+    InodeType::ExtendedSymlink => Inode::ExtendedSymlink(header, parse_extened_symlink(data)?),
+    InodeType::ExtendedBlockDevice => {
+      Inode::ExtendedBlockDevice(header, parse_extened_block_device(data)?)
+    }
+    InodeType::ExtendedCharDevice => {
+      Inode::ExtendedCharDevice(header, parse_extened_char_device(data)?)
+    }
+    InodeType::ExtendedFifo => Inode::ExtendedFifo(header, parse_extened_fifo(data)?),
+    InodeType::ExtendedSocket => Inode::ExtendedSocket(header, parse_extened_socket(data)?),
   };
 
-  Ok(())
+  Ok(inode)
 }
 
-fn parse_basic_directory(data: Vec<u8>) -> Result<Box<BasicDirectory>> {
-  if data.len() < BASIC_DIRECTORY_BODY_SIZE {
-    return Err(invalid_error!(format!(
-      "invalid BasicDirectory body size({} bytes), must >= {} bytes",
-      data.len(),
-      BASIC_DIRECTORY_BODY_SIZE
-    )));
+fn parse_basic_directory(mut data: &[u8]) -> Result<BasicDirectory> {
+  Ok(BasicDirectory {
+    block_idx: data.read_u32::<LittleEndian>()?,
+    nlink: data.read_u32::<LittleEndian>()?,
+    size: data.read_u16::<LittleEndian>()?,
+    offset: data.read_u16::<LittleEndian>()?,
+    parent_inode: data.read_u32::<LittleEndian>()?,
+  })
+}
+
+/// number of data blocks of a file, the tail end is stored in a fragment if it has one.
+pub fn block_count(size: u64, block_size: u32, fragment_block_idx: u32) -> u64 {
+  let block_size = block_size as u64;
+  if fragment_block_idx == NO_FRAGMENT {
+    size.div_ceil(block_size)
+  } else {
+    size / block_size
   }
-  let mut body = BasicDirectory::default();
-  (&*data).read(body.as_mut())?;
-  Ok(Box::new(body))
-}
-
-fn parse_basic_file(data: Vec<u8>, block_size: u32) -> Result<Box<BasicFile>> {
-  // TODO
-  if data.len() < BASIC_FILE_BODY_SIZE {
-    return Err(invalid_error!(format!(
-      "invalid BasicFile body size({} bytes), must >= {} bytes",
-      data.len(),
-      BASIC_FILE_BODY_SIZE
-    )));
+}
+
+fn parse_block_sizes(data: &mut &[u8], count: u64) -> Result<Vec<u32>> {
+  // don't trust the count for preallocation, it comes from the image
+  if data.len() as u64 / 4 < count {
+    return Err(std::io::Error::new(
+      ErrorKind::UnexpectedEof,
+      format!("block list needs {} entries, only {} bytes left", count, data.len()),
+    ));
   }
 
-  let mut body = BasicFile::default();
-  (&*data).read(body.as_mut())?;
+  let mut block_sizes = Vec::with_capacity(count as usize);
+  for _ in 0..count {
+    block_sizes.push(data.read_u32::<LittleEndian>()?);
+  }
+  Ok(block_sizes)
+}
+
+fn parse_basic_file(mut data: &[u8], block_size: u32) -> Result<BasicFile> {
+  let mut body = BasicFile {
+    block_idx: data.read_u32::<LittleEndian>()?,
+    fragment_block_idx: data.read_u32::<LittleEndian>()?,
+    offset: data.read_u32::<LittleEndian>()?,
+    size: data.read_u32::<LittleEndian>()?,
+    ..Default::default()
+  };
 
-  let mut block_list_size = body.size / block_size;
+  let count = block_count(body.size as u64, block_size, body.fragment_block_idx);
+  body.block_sizes = parse_block_sizes(&mut data, count)?;
 
-  if body.size % block_size > 0 && body.fragment_block_idx != 0xffffffff {
-    block_list_size += 1
+  Ok(body)
+}
+
+fn parse_target_path(data: &mut &[u8], target_size: u32) -> Result<Vec<u8>> {
+  if data.len() < target_size as usize {
+    return Err(std::io::Error::new(
+      ErrorKind::UnexpectedEof,
+      format!("symlink target needs {} bytes, only {} left", target_size, data.len()),
+    ));
   }
+  let mut target_path = vec![0u8; target_size as usize];
+  data.read_exact(&mut target_path)?;
+  Ok(target_path)
+}
+
+fn parse_basic_symlink(mut data: &[u8]) -> Result<BasicSymlink> {
+  let nlink = data.read_u32::<LittleEndian>()?;
+  let target_size = data.read_u32::<LittleEndian>()?;
+  let target_path = parse_target_path(&mut data, target_size)?;
+
+  Ok(BasicSymlink {
+    nlink,
+    target_size,
+    target_path,
+  })
+}
 
-  let extra = block_list_size * 4;
+fn parse_basic_block_device(mut data: &[u8]) -> Result<BasicBlockDevice> {
+  Ok(BasicBlockDevice {
+    nlink: data.read_u32::<LittleEndian>()?,
+    device: data.read_u32::<LittleEndian>()?,
+  })
+}
 
-  while data[BASIC_FILE_BODY_SIZE..].len() >= extra as usize {}
+fn parse_basic_char_device(mut data: &[u8]) -> Result<BasicCharDevice> {
+  Ok(BasicCharDevice {
+    nlink: data.read_u32::<LittleEndian>()?,
+    device: data.read_u32::<LittleEndian>()?,
+  })
+}
 
-  Ok(Box::new(body))
+fn parse_basic_fifo(mut data: &[u8]) -> Result<BasicFifo> {
+  Ok(BasicFifo {
+    nlink: data.read_u32::<LittleEndian>()?,
+  })
 }
 
-fn parse_basic_symlink() -> Result<Box<BasicSymlink>> {
-  // TODO: parse BasicSymlink body.
-  let body = BasicSymlink::default();
-  Ok(Box::new(body))
+fn parse_basic_socket(mut data: &[u8]) -> Result<BasicSocket> {
+  Ok(BasicSocket {
+    nlink: data.read_u32::<LittleEndian>()?,
+  })
 }
 
-fn parse_basic_block_device() -> Result<Box<BasicBlockDevice>> {
-  // TODO: parse BasicBlockDevice body.
-  let body = BasicBlockDevice::default();
-  Ok(Box::new(body))
+fn parse_extened_directory(mut data: &[u8]) -> Result<ExtendedDirectory> {
+  let mut body = ExtendedDirectory {
+    nlink: data.read_u32::<LittleEndian>()?,
+    size: data.read_u32::<LittleEndian>()?,
+    block_idx: data.read_u32::<LittleEndian>()?,
+    parent_inode: data.read_u32::<LittleEndian>()?,
+    inodex_count: data.read_u16::<LittleEndian>()?,
+    offset: data.read_u16::<LittleEndian>()?,
+    xattr_idx: data.read_u32::<LittleEndian>()?,
+    ..Default::default()
+  };
+
+  for _ in 0..body.inodex_count {
+    let index = data.read_u32::<LittleEndian>()?;
+    let start = data.read_u32::<LittleEndian>()?;
+    let name_size = data.read_u32::<LittleEndian>()?;
+    // names are at most 256 bytes, anything larger is corrupt
+    if name_size >= 256 {
+      return Err(invalid_error!(format!(
+        "invalid directory index name size {}",
+        name_size as u64 + 1
+      )));
+    }
+    let mut name = vec![0u8; name_size as usize + 1];
+    data.read_exact(&mut name)?;
+    body.index.push(DirectoryIndex { index, start, name });
+  }
+
+  Ok(body)
 }
 
-fn parse_basic_ipc() -> Result<Box<BasicSocket>> {
-  // TODO: parse BasicSocket/BasicFifo body.
-  let body = BasicSocket::default();
-  Ok(Box::new(body))
+fn parse_extened_file(mut data: &[u8], block_size: u32) -> Result<ExtendedFile> {
+  let mut body = ExtendedFile {
+    block_idx: data.read_u64::<LittleEndian>()?,
+    size: data.read_u64::<LittleEndian>()?,
+    sparse: data.read_u64::<LittleEndian>()?,
+    nlink: data.read_u32::<LittleEndian>()?,
+    fragment_block_idx: data.read_u32::<LittleEndian>()?,
+    offset: data.read_u32::<LittleEndian>()?,
+    xattr_idx: data.read_u32::<LittleEndian>()?,
+    ..Default::default()
+  };
+
+  let count = block_count(body.size, block_size, body.fragment_block_idx);
+  body.block_sizes = parse_block_sizes(&mut data, count)?;
+
+  Ok(body)
 }
 
-fn parse_extened_directory() -> Result<Box<ExtendedDirectory>> {
-  // TODO: parse ExtendedDirectory body.
-  let body = ExtendedDirectory::default();
-  Ok(Box::new(body))
+fn parse_extened_symlink(mut data: &[u8]) -> Result<ExtendedSymlink> {
+  let nlink = data.read_u32::<LittleEndian>()?;
+  let target_size = data.read_u32::<LittleEndian>()?;
+  let target_path = parse_target_path(&mut data, target_size)?;
+  let xattr_idx = data.read_u32::<LittleEndian>()?;
+
+  Ok(ExtendedSymlink {
+    nlink,
+    target_size,
+    target_path,
+    xattr_idx,
+  })
 }
 
-fn parse_extened_file() -> Result<Box<ExtendedFile>> {
-  // TODO: parse ExtendedFile body.
-  let body = ExtendedFile::default();
-  Ok(Box::new(body))
+fn parse_extened_block_device(mut data: &[u8]) -> Result<ExtendedBlock> {
+  Ok(ExtendedBlock {
+    nlink: data.read_u32::<LittleEndian>()?,
+    device: data.read_u32::<LittleEndian>()?,
+    xattr_idx: data.read_u32::<LittleEndian>()?,
+  })
 }
 
-fn parse_extened_symlink() -> Result<Box<ExtendedSymlink>> {
-  // TODO: parse ExtendedSymlink body.
-  let body = ExtendedSymlink::default();
-  Ok(Box::new(body))
+fn parse_extened_char_device(mut data: &[u8]) -> Result<ExtendedChar> {
+  Ok(ExtendedChar {
+    nlink: data.read_u32::<LittleEndian>()?,
+    device: data.read_u32::<LittleEndian>()?,
+    xattr_idx: data.read_u32::<LittleEndian>()?,
+  })
 }
 
-fn parse_extened_block_device() -> Result<Box<ExtendedBlock>> {
-  // TODO: parse ExtendedChar/ExtendedBlock body.
-  let body = ExtendedBlock::default();
-  Ok(Box::new(body))
+fn parse_extened_fifo(mut data: &[u8]) -> Result<ExtendedFifo> {
+  Ok(ExtendedFifo {
+    nlink: data.read_u32::<LittleEndian>()?,
+    xattr_idx: data.read_u32::<LittleEndian>()?,
+  })
 }
 
-fn parse_extened_ipc() -> Result<Box<ExtendedSocket>> {
-  // TODO: parse ExtendedSocket/ExtendedFifo body.
-  let body = ExtendedSocket::default();
-  Ok(Box::new(body))
+fn parse_extened_socket(mut data: &[u8]) -> Result<ExtendedSocket> {
+  Ok(ExtendedSocket {
+    nlink: data.read_u32::<LittleEndian>()?,
+    xattr_idx: data.read_u32::<LittleEndian>()?,
+  })
 }
 
 #[cfg(test)]
 mod tests {
   use crate::tests::*;
   use crate::*;
-  use std::io::Result;
+  use std::io::{ErrorKind, Result};
 
   #[test]
   // #[cfg_attr(not(feature = "gzip-sqs"), ignore)]
   fn test_get_inode() -> Result<()> {
-    // TODO
     let (mut reader, sb) = prepare_tests()?;
 
-    let block = sb.root_inode_ref.block;
-    let offset = sb.root_inode_ref.offset;
+    let inode = get_inode(&mut reader, &sb, sb.root_inode_ref)?;
+    assert!(inode.is_dir());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_parse_inode() -> Result<()> {
+    let raw = vec![
+      // header: BasicCharDevice, 0o620, uid 1, gid 2, mtime, inode 7
+      0x05, 0x0, 0x90, 0x01, 0x01, 0x0, 0x02, 0x0, 0x78, 0x56, 0x34, 0x12, 0x07, 0x0, 0x0, 0x0,
+      // body: nlink 1, device 4:1
+      0x01, 0x0, 0x0, 0x0, 0x01, 0x04, 0x0, 0x0,
+    ];
+
+    let header = parse_inode_header(&raw)?;
+    assert_eq!(header.inode_type, InodeType::BasicCharDevice);
+    assert_eq!(header.permissions, 0o620);
+    assert_eq!(header.uid_idx, 1);
+    assert_eq!(header.gid_idx, 2);
+    assert_eq!(header.modified_time, 0x1234_5678);
+    assert_eq!(header.inode_number, 7);
+
+    let inode = parse_inode_body(header, &raw[INODE_HEADER_SIZE..], 4096)?;
+    assert_eq!(inode.inode_type(), InodeType::BasicCharDevice);
+    assert!(!inode.is_dir());
+    match inode {
+      Inode::BasicCharDevice(_, body) => {
+        assert_eq!(body.nlink, 1);
+        assert_eq!(body.device, 0x0401);
+      }
+      _ => panic!("unexpected inode {:?}", inode),
+    }
+
+    assert!(parse_inode_header(&[0xff; INODE_HEADER_SIZE]).is_err());
+    assert!(parse_inode_body(header_of(InodeType::BasicDirectory), &raw[..4], 4096).is_err());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_parse_inode_trailers() -> Result<()> {
+    // BasicFile of 10000 bytes with a fragment: 2 blocks of 4096 bytes
+    let raw = vec![
+      0x60, 0x0, 0x0, 0x0, 0x03, 0x0, 0x0, 0x0, 0x10, 0x0, 0x0, 0x0, 0x10, 0x27, 0x0, 0x0, //
+      0x00, 0x08, 0x0, 0x0, 0x0, 0x10, 0x0, 0x01,
+    ];
+    match parse_inode_body(header_of(InodeType::BasicFile), &raw, 4096)? {
+      Inode::BasicFile(_, body) => {
+        assert_eq!(body.size, 10000);
+        assert_eq!(body.fragment_block_idx, 3);
+        assert_eq!(body.block_sizes, vec![0x800, 0x0100_1000]);
+      }
+      inode => panic!("unexpected inode {:?}", inode),
+    }
+    // the block list is incomplete
+    let err = parse_inode_body(header_of(InodeType::BasicFile), &raw[..20], 4096).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+    // ExtendedFile of 8193 bytes without fragment: 3 blocks, the last one sparse
+    let mut raw = vec![
+      0x60, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x01, 0x20, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, //
+      0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x01, 0x0, 0x0, 0x0, 0xff, 0xff, 0xff, 0xff, //
+      0x0, 0x0, 0x0, 0x0, 0x02, 0x0, 0x0, 0x0,
+    ];
+    raw.extend(&[0x10, 0x0, 0x0, 0x0, 0x20, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0]);
+    match parse_inode_body(header_of(InodeType::ExtendedFile), &raw, 4096)? {
+      Inode::ExtendedFile(_, body) => {
+        assert_eq!(body.size, 8193);
+        assert_eq!(body.xattr_idx, 2);
+        assert_eq!(body.block_sizes, vec![0x10, 0x20, 0]);
+      }
+      inode => panic!("unexpected inode {:?}", inode),
+    }
 
-    get_inode(
-      &mut reader,
-      sb,
-      block as u32,
-      offset as u32,
-      InodeType::BasicDirectory,
-    )?;
+    // ExtendedSymlink, the xattr index follows the target
+    let raw = vec![
+      0x01, 0x0, 0x0, 0x0, 0x04, 0x0, 0x0, 0x0, b'/', b'b', b'i', b'n', 0x05, 0x0, 0x0, 0x0,
+    ];
+    match parse_inode_body(header_of(InodeType::ExtendedSymlink), &raw, 4096)? {
+      Inode::ExtendedSymlink(_, body) => {
+        assert_eq!(body.target_path, b"/bin");
+        assert_eq!(body.xattr_idx, 5);
+      }
+      inode => panic!("unexpected inode {:?}", inode),
+    }
+
+    // ExtendedDirectory with one index entry
+    let raw = vec![
+      0x02, 0x0, 0x0, 0x0, 0x40, 0x0, 0x0, 0x0, 0x0, 0x01, 0x0, 0x0, 0x01, 0x0, 0x0, 0x0, //
+      0x01, 0x0, 0x10, 0x0, 0xff, 0xff, 0xff, 0xff, //
+      0x0c, 0x0, 0x0, 0x0, 0x0, 0x01, 0x0, 0x0, 0x02, 0x0, 0x0, 0x0, b'u', b's', b'r',
+    ];
+    match parse_inode_body(header_of(InodeType::ExtendedDirectory), &raw, 4096)? {
+      Inode::ExtendedDirectory(_, body) => {
+        assert_eq!(body.size, 0x40);
+        assert_eq!(body.offset, 0x10);
+        assert_eq!(body.index.len(), 1);
+        assert_eq!(body.index[0].index, 12);
+        assert_eq!(body.index[0].start, 0x100);
+        assert_eq!(body.index[0].name, b"usr");
+      }
+      inode => panic!("unexpected inode {:?}", inode),
+    }
 
     Ok(())
   }
 
+  #[test]
+  fn test_inode_to_bytes() -> Result<()> {
+    let raw = vec![
+      0x05, 0x0, 0x90, 0x01, 0x01, 0x0, 0x02, 0x0, 0x78, 0x56, 0x34, 0x12, 0x07, 0x0, 0x0, 0x0,
+      0x01, 0x0, 0x0, 0x0, 0x01, 0x04, 0x0, 0x0,
+    ];
+    let inode = parse_inode_body(parse_inode_header(&raw)?, &raw[INODE_HEADER_SIZE..], 4096)?;
+    assert_eq!(inode.to_bytes(), raw);
+    assert_eq!(device_number(4, 1), 0x0401);
+    assert_eq!(device_number(0x123, 0x45678), 0x4561_2378);
+
+    let inode = Inode::ExtendedDirectory(
+      header_of(InodeType::ExtendedDirectory),
+      ExtendedDirectory {
+        nlink: 2,
+        size: 0x40,
+        offset: 0x10,
+        xattr_idx: 3,
+        index: vec![DirectoryIndex {
+          index: 12,
+          start: 0x100,
+          name: b"usr".to_vec(),
+        }],
+        ..Default::default()
+      },
+    );
+    let raw = inode.to_bytes();
+    match parse_inode_body(parse_inode_header(&raw)?, &raw[INODE_HEADER_SIZE..], 4096)? {
+      Inode::ExtendedDirectory(_, body) => {
+        assert_eq!(body.inodex_count, 1);
+        assert_eq!(body.xattr_idx, 3);
+        assert_eq!(body.index[0].name, b"usr");
+      }
+      inode => panic!("unexpected inode {:?}", inode),
+    }
+
+    Ok(())
+  }
+
+  fn header_of(inode_type: InodeType) -> InodeHeader {
+    InodeHeader {
+      inode_type,
+      ..Default::default()
+    }
+  }
+
   #[test]
   fn test_inode_type_struct_size() -> Result<()> {
     assert_eq!(BASIC_DIRECTORY_BODY_SIZE, 16);
@@ -1,34 +1,95 @@
-use crate::compress::Algorithm;
-use crate::SqsIoReader;
+use crate::compress::{read_compressor_options, Algorithm, CompressorOptions};
+use crate::{invalid_error, SqsIoRead, SquashfsError};
+use byteorder::{ByteOrder, LittleEndian};
 use prettytable::Table;
+use std::convert::TryFrom;
 use std::fmt;
 use std::io::Result;
 
 pub const MAGIC_NUMBER: u32 = 0x7371_7368;
 pub const VERSION_MAJOR: u16 = 4;
 pub const VERSION_MINOR: u16 = 0;
+pub const MIN_BLOCK_SIZE: u32 = 4096;
+pub const MAX_BLOCK_SIZE: u32 = 1024 * 1024;
+/// The start of an optional table which is not stored
+pub const NO_TABLE: u64 = 0xffff_ffff_ffff_ffff;
+
+/// A superblock which can't belong to a valid archive.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SuperblockError {
+  BadMagic(u32),
+  UnsupportedVersion { major: u16, minor: u16 },
+  UnknownCompressor(u16),
+  UnknownFlags(u16),
+  /// Not a power of two between 4 KiB and 1 MiB
+  BadBlockSize(u32),
+  BlockLogMismatch { block_size: u32, block_log: u16 },
+  NoInodes,
+  /// The table starts inside the superblock or after `bytes_used`
+  TableOutOfBounds { table: &'static str, start: u64, bytes_used: u64 },
+  /// The tables are not stored in the order written by mksquashfs
+  TablesOutOfOrder { table: &'static str, start: u64, previous: &'static str, previous_start: u64 },
+  /// The root inode reference points after the inode table
+  RootOutOfInodeTable(InodeRef),
+}
 
-#[macro_export]
-macro_rules! impl_converter {
-  ($T: ty) => {
-    impl AsRef<[u8]> for $T {
-      #[inline]
-      fn as_ref(&self) -> &[u8] {
-        use std::mem::size_of;
-        let ptr = self as *const $T as *const u8;
-        unsafe { &*std::slice::from_raw_parts(ptr, size_of::<$T>()) }
+impl fmt::Display for SuperblockError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      SuperblockError::BadMagic(magic) => {
+        write!(f, "bad magic {:#x}, not a squashfs archive", magic)
       }
-    }
-
-    impl AsMut<[u8]> for $T {
-      #[inline]
-      fn as_mut(&mut self) -> &mut [u8] {
-        use std::mem::size_of;
-        let ptr = self as *mut $T as *mut u8;
-        unsafe { &mut *std::slice::from_raw_parts_mut(ptr, size_of::<$T>()) }
+      SuperblockError::UnsupportedVersion { major, minor } => {
+        write!(f, "unsupported squashfs version {}.{}", major, minor)
+      }
+      SuperblockError::UnknownCompressor(id) => write!(f, "unknown compressor id {}", id),
+      SuperblockError::UnknownFlags(flags) => write!(f, "unknown superblock flags {:#x}", flags),
+      SuperblockError::BadBlockSize(size) => write!(
+        f,
+        "block size {} is not a power of two between {} and {}",
+        size, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE
+      ),
+      SuperblockError::BlockLogMismatch {
+        block_size,
+        block_log,
+      } => write!(
+        f,
+        "block log {} doesn't match block size {}",
+        block_log, block_size
+      ),
+      SuperblockError::NoInodes => write!(f, "the archive has no inodes"),
+      SuperblockError::TableOutOfBounds {
+        table,
+        start,
+        bytes_used,
+      } => write!(
+        f,
+        "{} table start {} out of the archive ({}..{})",
+        table, start, SUPERBLOCK_SIZE, bytes_used
+      ),
+      SuperblockError::TablesOutOfOrder {
+        table,
+        start,
+        previous,
+        previous_start,
+      } => write!(
+        f,
+        "{} table start {} is before {} table start {}",
+        table, start, previous, previous_start
+      ),
+      SuperblockError::RootOutOfInodeTable(root) => {
+        write!(f, "root inode {} is out of the inode table", root)
       }
     }
-  };
+  }
+}
+
+impl std::error::Error for SuperblockError {}
+
+impl From<SuperblockError> for std::io::Error {
+  fn from(e: SuperblockError) -> Self {
+    SquashfsError::from(e).into()
+  }
 }
 
 macro_rules! has_flag {
@@ -40,7 +101,9 @@ macro_rules! has_flag {
   };
 }
 
-#[repr(C)]
+/// The on-disk size of the superblock.
+pub const SUPERBLOCK_SIZE: usize = 96;
+
 #[derive(Debug, Default)]
 pub struct Superblock {
   /// Must match the value of 0x73717368 to be considered a squashfs archive
@@ -104,20 +167,162 @@ pub struct Superblock {
 
   /// The byte offset at which the export table starts
   pub export_table_start: u64,
-}
 
-impl_converter!(Superblock);
+  /// The options stored after the superblock if `Flags::COMPRESSOR_OPTIONS` is set,
+  /// not part of the on-disk superblock
+  pub compressor_options: Option<CompressorOptions>,
+}
 
 impl Superblock {
   pub fn new() -> Self {
     Self::default()
   }
 
-  pub fn load(&mut self, r: &mut SqsIoReader) -> Result<()> {
-    r.read_exact(self.as_mut())?;
+  /// Read and validate the superblock at the current position of `r`, and the
+  /// compressor options following it.
+  pub fn load(&mut self, r: &mut dyn SqsIoRead) -> Result<()> {
+    let mut data = [0u8; SUPERBLOCK_SIZE];
+    r.read_exact(&mut data)?;
+    *self = Self::parse(&data)?;
+    self.validate()?;
+    self.compressor_options = read_compressor_options(r, self)?;
+    Ok(())
+  }
+
+  /// Check the fields which can't be trusted in an image from an unknown source.
+  /// 1. The magic and version;
+  /// 2. `block_size` is a power of two in 4KiB..1MiB and agrees with `block_log`;
+  /// 3. The tables start after the superblock, before `bytes_used`, in the order
+  ///    inode, directory, fragment, export, id, xattr. Missing optional tables are skipped;
+  /// 4. The root inode is in the inode table.
+  pub fn validate(&self) -> std::result::Result<(), SuperblockError> {
+    if self.magic != MAGIC_NUMBER {
+      return Err(SuperblockError::BadMagic(self.magic));
+    }
+    if self.version_major != VERSION_MAJOR || self.version_minor != VERSION_MINOR {
+      return Err(SuperblockError::UnsupportedVersion {
+        major: self.version_major,
+        minor: self.version_minor,
+      });
+    }
+    if !self.block_size.is_power_of_two()
+      || self.block_size < MIN_BLOCK_SIZE
+      || self.block_size > MAX_BLOCK_SIZE
+    {
+      return Err(SuperblockError::BadBlockSize(self.block_size));
+    }
+    if self.block_size.trailing_zeros() != self.block_log as u32 {
+      return Err(SuperblockError::BlockLogMismatch {
+        block_size: self.block_size,
+        block_log: self.block_log,
+      });
+    }
+    if self.inode_count == 0 {
+      return Err(SuperblockError::NoInodes);
+    }
+
+    let tables = [
+      ("inode", self.inode_table_start, true),
+      ("directory", self.directory_table_start, true),
+      ("fragment", self.fragment_table_start, false),
+      ("export", self.export_table_start, false),
+      ("id", self.id_table_start, true),
+      ("xattr", self.xattr_id_table_start, false),
+    ];
+    let mut previous: Option<(&'static str, u64)> = None;
+    for (table, start, required) in tables.iter() {
+      if !required && *start == NO_TABLE {
+        continue;
+      }
+      if *start < SUPERBLOCK_SIZE as u64 || *start > self.bytes_used {
+        return Err(SuperblockError::TableOutOfBounds {
+          table,
+          start: *start,
+          bytes_used: self.bytes_used,
+        });
+      }
+      if let Some((previous, previous_start)) = previous {
+        if *start < previous_start {
+          return Err(SuperblockError::TablesOutOfOrder {
+            table,
+            start: *start,
+            previous,
+            previous_start,
+          });
+        }
+      }
+      previous = Some((table, *start));
+    }
+
+    if self.inode_table_start + self.root_inode_ref.block() as u64 >= self.directory_table_start {
+      return Err(SuperblockError::RootOutOfInodeTable(self.root_inode_ref));
+    }
+
     Ok(())
   }
 
+  /// Decode the on-disk superblock, without the compressor options.
+  pub fn parse(data: &[u8]) -> Result<Self> {
+    if data.len() < SUPERBLOCK_SIZE {
+      return Err(invalid_error!(format!(
+        "invalid superblock size({} bytes), must >= {} bytes",
+        data.len(),
+        SUPERBLOCK_SIZE
+      )));
+    }
+
+    let compressor = LittleEndian::read_u16(&data[20..22]);
+    let flags = LittleEndian::read_u16(&data[24..26]);
+    Ok(Superblock {
+      magic: LittleEndian::read_u32(&data[0..4]),
+      inode_count: LittleEndian::read_u32(&data[4..8]),
+      modification_time: LittleEndian::read_u32(&data[8..12]),
+      block_size: LittleEndian::read_u32(&data[12..16]),
+      fragment_entry_count: LittleEndian::read_u32(&data[16..20]),
+      compressor: Algorithm::try_from(compressor)
+        .map_err(|_| SuperblockError::UnknownCompressor(compressor))?,
+      block_log: LittleEndian::read_u16(&data[22..24]),
+      flags: Flags::from_bits(flags).ok_or(SuperblockError::UnknownFlags(flags))?,
+      id_count: LittleEndian::read_u16(&data[26..28]),
+      version_major: LittleEndian::read_u16(&data[28..30]),
+      version_minor: LittleEndian::read_u16(&data[30..32]),
+      root_inode_ref: InodeRef(LittleEndian::read_u64(&data[32..40])),
+      bytes_used: LittleEndian::read_u64(&data[40..48]),
+      id_table_start: LittleEndian::read_u64(&data[48..56]),
+      xattr_id_table_start: LittleEndian::read_u64(&data[56..64]),
+      inode_table_start: LittleEndian::read_u64(&data[64..72]),
+      directory_table_start: LittleEndian::read_u64(&data[72..80]),
+      fragment_table_start: LittleEndian::read_u64(&data[80..88]),
+      export_table_start: LittleEndian::read_u64(&data[88..96]),
+      compressor_options: None,
+    })
+  }
+
+  /// Encode the on-disk superblock, without the compressor options.
+  pub fn to_bytes(&self) -> [u8; SUPERBLOCK_SIZE] {
+    let mut data = [0u8; SUPERBLOCK_SIZE];
+    LittleEndian::write_u32(&mut data[0..4], self.magic);
+    LittleEndian::write_u32(&mut data[4..8], self.inode_count);
+    LittleEndian::write_u32(&mut data[8..12], self.modification_time);
+    LittleEndian::write_u32(&mut data[12..16], self.block_size);
+    LittleEndian::write_u32(&mut data[16..20], self.fragment_entry_count);
+    LittleEndian::write_u16(&mut data[20..22], self.compressor as u16);
+    LittleEndian::write_u16(&mut data[22..24], self.block_log);
+    LittleEndian::write_u16(&mut data[24..26], self.flags.bits());
+    LittleEndian::write_u16(&mut data[26..28], self.id_count);
+    LittleEndian::write_u16(&mut data[28..30], self.version_major);
+    LittleEndian::write_u16(&mut data[30..32], self.version_minor);
+    LittleEndian::write_u64(&mut data[32..40], self.root_inode_ref.0);
+    LittleEndian::write_u64(&mut data[40..48], self.bytes_used);
+    LittleEndian::write_u64(&mut data[48..56], self.id_table_start);
+    LittleEndian::write_u64(&mut data[56..64], self.xattr_id_table_start);
+    LittleEndian::write_u64(&mut data[64..72], self.inode_table_start);
+    LittleEndian::write_u64(&mut data[72..80], self.directory_table_start);
+    LittleEndian::write_u64(&mut data[80..88], self.fragment_table_start);
+    LittleEndian::write_u64(&mut data[88..96], self.export_table_start);
+    data
+  }
+
   pub fn to_table(&self) -> Table {
     table!(
       ["Field", "Value"],
@@ -127,6 +332,13 @@ impl Superblock {
       ["block_size", self.block_size],
       ["fragment_entry_count", self.fragment_entry_count],
       ["compressor", self.compressor],
+      [
+        "compressor_options",
+        match &self.compressor_options {
+          Some(options) => options.to_string(),
+          None => String::from("-"),
+        }
+      ],
       ["block_log", self.block_log],
       ["flags", self.flags.to_table()],
       ["id_count", self.id_count],
@@ -225,22 +437,39 @@ impl Default for Flags {
 
 impl fmt::Display for Flags {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "{}", format!("{:?}", self))?;
+    write!(f, "{:?}", self)?;
     Ok(())
   }
 }
 
-#[repr(C)]
-#[derive(Debug, Default)]
-pub struct InodeRef {
-  pub offset: u16,
-  pub block: u16,
-  padding: u32,
+/// A reference to an inode: the upper 48 bits hold the offset of the metadata
+/// block relative to the inode table start, the lower 16 bits the offset of the
+/// inode within the uncompressed block.
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct InodeRef(pub u64);
+
+impl InodeRef {
+  pub fn new(block: u32, offset: u16) -> Self {
+    InodeRef(((block as u64) << 16) | offset as u64)
+  }
+
+  /// The offset of the metadata block relative to `Superblock::inode_table_start`
+  #[inline]
+  pub fn block(&self) -> u32 {
+    (self.0 >> 16) as u32
+  }
+
+  /// The offset of the inode within the uncompressed metadata block
+  #[inline]
+  pub fn offset(&self) -> u16 {
+    (self.0 & 0xffff) as u16
+  }
 }
 
 impl fmt::Display for InodeRef {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "{}", format!("{:?}", self))?;
+    write!(f, "{}:{}", self.block(), self.offset())?;
     Ok(())
   }
 }
@@ -257,7 +486,12 @@ mod tests {
   fn read_superblock() -> Result<()> {
     debug!("Superblock size: {}", size_of::<Superblock>());
     let (_, sb) = prepare_tests()?;
-    sb.to_table().printstd();
+    let table = sb.to_table();
+    let cell = |row: usize, col: usize| table[row][col].get_content();
+    assert_eq!(cell(1, 0), "magic");
+    assert_eq!(cell(6, 0), "compressor");
+    assert_eq!(cell(6, 1), sb.compressor.to_string());
+    assert_eq!(cell(7, 0), "compressor_options");
 
     assert_eq!(sb.magic, MAGIC_NUMBER);
     assert_eq!(sb.version_major, VERSION_MAJOR);
@@ -265,4 +499,128 @@ mod tests {
 
     Ok(())
   }
+
+  #[test]
+  fn test_load_compressor_options() -> Result<()> {
+    let mut image = build_test_image()[..SUPERBLOCK_SIZE].to_vec();
+    // zstd with the COMPRESSOR_OPTIONS flag, followed by an uncompressed metadata block
+    LittleEndian::write_u16(&mut image[20..22], 6);
+    LittleEndian::write_u16(&mut image[24..26], 0x0613);
+    image.extend(&[0x04, 0x80, 0x13, 0x0, 0x0, 0x0]);
+
+    let mut sb = Superblock::new();
+    sb.load(&mut std::io::Cursor::new(image))?;
+    assert_eq!(sb.compressor, Algorithm::Zstd);
+    assert_eq!(
+      sb.compressor_options,
+      Some(CompressorOptions::Zstd(crate::compress::ZstdOptions {
+        compression_level: 19
+      }))
+    );
+    let table = sb.to_table();
+    assert_eq!(table[6][1].get_content(), "Zstd");
+    assert_eq!(
+      table[7][1].get_content(),
+      "Zstd(ZstdOptions { compression_level: 19 })"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_superblock_bytes() -> Result<()> {
+    // every field has distinct bytes, so a native endian read would not match on big endian hosts
+    let mut raw = vec![];
+    raw.extend(b"hsqs");
+    raw.extend(&[0x04, 0x03, 0x02, 0x01]); // inode_count
+    raw.extend(&[0x78, 0x56, 0x34, 0x12]); // modification_time
+    raw.extend(&[0x0, 0x0, 0x02, 0x0]); // block_size
+    raw.extend(&[0x02, 0x01, 0x0, 0x0]); // fragment_entry_count
+    raw.extend(&[0x04, 0x0, 0x11, 0x0]); // xz, block_log
+    raw.extend(&[0x30, 0x0, 0x02, 0x01]); // flags, id_count
+    raw.extend(&[0x04, 0x0, 0x0, 0x0]); // version
+    raw.extend(&[0x20, 0x01, 0x30, 0x02, 0x0, 0x0, 0x0, 0x0]); // root_inode_ref
+    for start in 1..=7u8 {
+      // bytes_used and the table starts
+      raw.extend(&[start, 0x0, 0x0, 0x10 - start, 0x0, 0x0, 0x0, 0x0]);
+    }
+
+    let sb = Superblock::parse(&raw)?;
+    assert_eq!(sb.magic, MAGIC_NUMBER);
+    assert_eq!(sb.inode_count, 0x0102_0304);
+    assert_eq!(sb.modification_time, 0x1234_5678);
+    assert_eq!(sb.block_size, 1 << 17);
+    assert_eq!(sb.fragment_entry_count, 0x0102);
+    assert_eq!(sb.compressor, Algorithm::Xz);
+    assert_eq!(sb.block_log, 17);
+    assert_eq!(sb.flags, Flags::NO_FRAGMENTS | Flags::ALWAYS_FRAGMENTS);
+    assert_eq!(sb.id_count, 0x0102);
+    assert_eq!(sb.version_major, VERSION_MAJOR);
+    assert_eq!(sb.root_inode_ref, InodeRef::new(0x0230, 0x0120));
+    assert_eq!(sb.bytes_used, 0x0f00_0001);
+    assert_eq!(sb.export_table_start, 0x0900_0007);
+    assert_eq!(&sb.to_bytes()[..], &raw[..]);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_validate() -> Result<()> {
+    let image = build_test_image();
+    let sb = Superblock::parse(&image)?;
+    assert_eq!(sb.validate(), Ok(()));
+
+    let check = |f: &dyn Fn(&mut Superblock)| {
+      let mut sb = Superblock::parse(&image).unwrap();
+      f(&mut sb);
+      sb.validate().unwrap_err()
+    };
+    assert_eq!(check(&|sb| sb.magic = 0), SuperblockError::BadMagic(0));
+    assert_eq!(
+      check(&|sb| sb.version_major = 3),
+      SuperblockError::UnsupportedVersion { major: 3, minor: 0 }
+    );
+    assert_eq!(
+      check(&|sb| sb.block_size = 3000),
+      SuperblockError::BadBlockSize(3000)
+    );
+    assert_eq!(
+      check(&|sb| sb.block_size = 1 << 21),
+      SuperblockError::BadBlockSize(1 << 21)
+    );
+    assert_eq!(
+      check(&|sb| sb.block_log = 13),
+      SuperblockError::BlockLogMismatch {
+        block_size: 4096,
+        block_log: 13
+      }
+    );
+    assert!(matches!(
+      check(&|sb| sb.id_table_start = sb.bytes_used + 1),
+      SuperblockError::TableOutOfBounds { table: "id", .. }
+    ));
+    assert!(matches!(
+      check(&|sb| sb.directory_table_start = 100),
+      SuperblockError::TablesOutOfOrder {
+        table: "directory",
+        previous: "inode",
+        ..
+      }
+    ));
+    assert!(matches!(
+      check(&|sb| sb.root_inode_ref = InodeRef::new(4096, 0)),
+      SuperblockError::RootOutOfInodeTable(_)
+    ));
+
+    let mut bad = image.clone();
+    bad[20] = 9;
+    let err = Superblock::parse(&bad).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(matches!(
+      SquashfsError::from(err),
+      SquashfsError::InvalidSuperblock(SuperblockError::UnknownCompressor(9))
+    ));
+
+    Ok(())
+  }
 }
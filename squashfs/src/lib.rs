@@ -37,6 +37,8 @@
 //! - https://dr-emann.github.io/squashfs/
 //!
 
+#![forbid(unsafe_code)]
+
 #[macro_use]
 extern crate bitflags;
 // #[macro_use]
@@ -50,50 +52,48 @@ extern crate prettytable;
 #[macro_use]
 extern crate log;
 
-use flexi_logger::{colored_opt_format, Logger};
-use std::fs::File;
-use std::io::{Read, Result, Seek};
+use std::io::{Read, Seek};
 
+pub mod archive;
 pub mod compress;
+pub mod directory;
+pub mod export;
+pub mod file;
 pub mod fragment;
 pub mod inode;
 pub mod layout;
+pub mod lookup;
 pub mod metadata;
 pub mod uidgids;
 pub mod utils;
+pub mod walk;
+pub mod writer;
 pub mod xattrs;
 
+pub use archive::*;
+pub use directory::*;
+pub use export::*;
+pub use file::*;
 pub use fragment::*;
 pub use inode::*;
 pub use layout::*;
-pub use log::LevelFilter;
+pub use lookup::*;
 pub use metadata::*;
 pub use uidgids::*;
 pub use utils::errors::*;
+pub use walk::*;
+pub use writer::*;
 pub use xattrs::*;
 
 pub trait SqsIoRead: Read + Seek {}
 
 pub type SqsIoReader = Box<dyn SqsIoRead>;
 
-impl SqsIoRead for File {}
-
-pub fn set_logging(level: LevelFilter) -> Result<()> {
-    Logger::try_with_env_or_str("trace")
-        .unwrap()
-        .format(colored_opt_format)
-        .start()
-        .map_err(|e| map_other_error!(e))?;
-
-    log::set_max_level(level);
-
-    Ok(())
-}
+impl<T: Read + Seek> SqsIoRead for T {}
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::env;
     use std::fs::File;
     use std::io::Result;
     use std::sync::Once;
@@ -102,13 +102,13 @@ mod tests {
 
     pub fn prepare_tests() -> Result<(SqsIoReader, Superblock)> {
         TEST_LOGGER_INIT.call_once(move || {
-            if let Ok(level) = env::var("RUST_LOG") {
-                if level == "trace" {
-                    set_logging(LevelFilter::Trace).unwrap();
-                }
-            } else {
-                set_logging(LevelFilter::Debug).unwrap();
-            }
+            // the logger is kept for the whole test run
+            let logger = flexi_logger::Logger::try_with_env_or_str("debug")
+                .unwrap()
+                .format(flexi_logger::colored_opt_format)
+                .start()
+                .unwrap();
+            std::mem::forget(logger);
         });
 
         let test_sqs_file =
@@ -117,7 +117,7 @@ mod tests {
             } else {
                 String::from("tests/data/gzip-sqs")
             };
-        let f = File::open(test_sqs_file).map_err(|e| map_error!(e))?;
+        let f = File::open(test_sqs_file)?;
         let mut reader = Box::new(f.try_clone()?) as SqsIoReader;
 
         let mut sb = Superblock::new();
@@ -125,4 +125,145 @@ mod tests {
 
         Ok((reader, sb))
     }
+
+    /// A small uncompressed image:
+    /// ```text
+    /// /
+    /// |-- hello       "hello world\n"
+    /// |-- link -> hello
+    /// |-- loop -> loop
+    /// `-- sub/
+    ///     `-- deep    "deep\n"
+    /// ```
+    pub fn build_test_image() -> Vec<u8> {
+        use byteorder::{LittleEndian, WriteBytesExt};
+
+        fn header(buf: &mut Vec<u8>, inode_type: u16, permissions: u16, inode_number: u32) {
+            buf.write_u16::<LittleEndian>(inode_type).unwrap();
+            buf.write_u16::<LittleEndian>(permissions).unwrap();
+            buf.write_u16::<LittleEndian>(0).unwrap();
+            buf.write_u16::<LittleEndian>(0).unwrap();
+            buf.write_u32::<LittleEndian>(0x6000_0000).unwrap();
+            buf.write_u32::<LittleEndian>(inode_number).unwrap();
+        }
+        fn file(buf: &mut Vec<u8>, inode_number: u32, start: u32, content: &[u8]) {
+            header(buf, 2, 0o644, inode_number);
+            for v in &[start, 0xffff_ffff, 0, content.len() as u32] {
+                buf.write_u32::<LittleEndian>(*v).unwrap();
+            }
+            // a single uncompressed block
+            buf.write_u32::<LittleEndian>(content.len() as u32 | 0x0100_0000)
+                .unwrap();
+        }
+        fn dir(buf: &mut Vec<u8>, inode_number: u32, size: u16, offset: u16, parent: u32) {
+            header(buf, 1, 0o755, inode_number);
+            buf.write_u32::<LittleEndian>(0).unwrap();
+            buf.write_u32::<LittleEndian>(2).unwrap();
+            buf.write_u16::<LittleEndian>(size + 3).unwrap();
+            buf.write_u16::<LittleEndian>(offset).unwrap();
+            buf.write_u32::<LittleEndian>(parent).unwrap();
+        }
+        fn listing(buf: &mut Vec<u8>, base: u32, entries: &[(u16, u16, u32, &str)]) {
+            buf.write_u32::<LittleEndian>(entries.len() as u32 - 1).unwrap();
+            buf.write_u32::<LittleEndian>(0).unwrap();
+            buf.write_u32::<LittleEndian>(base).unwrap();
+            for (offset, inode_type, inode_number, name) in entries {
+                buf.write_u16::<LittleEndian>(*offset).unwrap();
+                buf.write_i16::<LittleEndian>((inode_number - base) as i16)
+                    .unwrap();
+                buf.write_u16::<LittleEndian>(*inode_type).unwrap();
+                buf.write_u16::<LittleEndian>(name.len() as u16 - 1).unwrap();
+                buf.extend(name.as_bytes());
+            }
+        }
+        fn symlink(buf: &mut Vec<u8>, inode_number: u32, target: &str) {
+            header(buf, 3, 0o777, inode_number);
+            buf.write_u32::<LittleEndian>(1).unwrap();
+            buf.write_u32::<LittleEndian>(target.len() as u32).unwrap();
+            buf.extend(target.as_bytes());
+        }
+        fn meta_block(image: &mut Vec<u8>, data: &[u8]) {
+            image
+                .write_u16::<LittleEndian>(data.len() as u16 | 0x8000)
+                .unwrap();
+            image.extend(data);
+        }
+
+        let hello = b"hello world\n";
+        let deep = b"deep\n";
+        let data_start = 96u32;
+
+        let mut dirs = vec![];
+        listing(&mut dirs, 3, &[(65, 2, 3, "deep")]);
+        let sub_size = dirs.len() as u16;
+        listing(
+            &mut dirs,
+            1,
+            &[
+                (0, 2, 1, "hello"),
+                (36, 3, 2, "link"),
+                (165, 3, 6, "loop"),
+                (101, 1, 4, "sub"),
+            ],
+        );
+        let root_size = dirs.len() as u16 - sub_size;
+
+        let mut inodes = vec![];
+        let mut exports = vec![];
+        exports.write_u64::<LittleEndian>(inodes.len() as u64).unwrap();
+        file(&mut inodes, 1, data_start, hello);
+        exports.write_u64::<LittleEndian>(inodes.len() as u64).unwrap();
+        symlink(&mut inodes, 2, "hello");
+        exports.write_u64::<LittleEndian>(inodes.len() as u64).unwrap();
+        file(&mut inodes, 3, data_start + hello.len() as u32, deep);
+        exports.write_u64::<LittleEndian>(inodes.len() as u64).unwrap();
+        dir(&mut inodes, 4, sub_size, 0, 5);
+        let root_offset = inodes.len() as u16;
+        exports.write_u64::<LittleEndian>(inodes.len() as u64).unwrap();
+        dir(&mut inodes, 5, root_size, sub_size, 7);
+        exports.write_u64::<LittleEndian>(inodes.len() as u64).unwrap();
+        symlink(&mut inodes, 6, "loop");
+
+        let mut image = vec![0u8; data_start as usize];
+        image.extend(hello);
+        image.extend(deep);
+        let inode_table_start = image.len() as u64;
+        meta_block(&mut image, &inodes);
+        let directory_table_start = image.len() as u64;
+        meta_block(&mut image, &dirs);
+        let fragment_table_start = image.len() as u64;
+        let export_block = image.len() as u64;
+        meta_block(&mut image, &exports);
+        let export_table_start = image.len() as u64;
+        image.write_u64::<LittleEndian>(export_block).unwrap();
+        let id_block = image.len() as u64;
+        meta_block(&mut image, &1000u32.to_le_bytes());
+        let id_table_start = image.len() as u64;
+        image.write_u64::<LittleEndian>(id_block).unwrap();
+        let bytes_used = image.len() as u64;
+
+        let mut sb = &mut image[..96];
+        for v in &[MAGIC_NUMBER, 6, 0x6000_0000, 4096, 0] {
+            sb.write_u32::<LittleEndian>(*v).unwrap();
+        }
+        // gzip, block_log, flags, id_count, version
+        for v in &[1u16, 12, 0x0293, 1, VERSION_MAJOR, VERSION_MINOR] {
+            sb.write_u16::<LittleEndian>(*v).unwrap();
+        }
+        let root = InodeRef::new(0, root_offset);
+        for v in &[
+            root.0,
+            bytes_used,
+            id_table_start,
+            NO_XATTR_SUPERBLOCK_FLAG,
+            inode_table_start,
+            directory_table_start,
+            fragment_table_start,
+            export_table_start,
+        ] {
+            sb.write_u64::<LittleEndian>(*v).unwrap();
+        }
+
+        image
+    }
 }
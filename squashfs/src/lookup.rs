@@ -0,0 +1,182 @@
+use super::*;
+use std::collections::{HashMap, VecDeque};
+use std::io::Result;
+
+/// The maximum number of symlinks followed while resolving a single path, same as Linux.
+pub const MAX_SYMLINK_FOLLOWS: usize = 40;
+
+#[derive(Clone, Copy, Debug)]
+pub struct LookupOptions {
+  /// Follow a symlink in the last path component. Symlinks in the
+  /// intermediate components are always followed.
+  pub follow_symlinks: bool,
+}
+
+impl Default for LookupOptions {
+  fn default() -> Self {
+    LookupOptions {
+      follow_symlinks: true,
+    }
+  }
+}
+
+fn split_path(path: &[u8]) -> impl Iterator<Item = Vec<u8>> + '_ {
+  path.split(|c| *c == b'/').map(|c| c.to_vec())
+}
+
+/// Find the entry `name` in the directory `dir`.
+pub fn find_entry(
+  r: &mut dyn SqsIoRead,
+  sb: &Superblock,
+  dir: &dyn DirectoryInode,
+  name: &[u8],
+) -> Result<Option<DirEntry>> {
+  for entry in read_directory(r, sb, dir)? {
+    let entry = entry?;
+    if entry.name == name {
+      return Ok(Some(entry));
+    }
+  }
+  Ok(None)
+}
+
+/// resolve a path to an inode.
+/// 1. Start from `Superblock::root_inode_ref`, relative paths are resolved from the root too;
+/// 2. Look up each component in the listing of the current directory;
+/// 3. `..` goes to the `parent_inode` of the current directory. It is an inode number,
+///    the walk started at the root and so went through the parent already, its reference
+///    is taken from there. `..` of the root is the root itself;
+/// 4. Symlinks are replaced by their target, absolute targets restart from the root.
+pub fn lookup(
+  r: &mut dyn SqsIoRead,
+  sb: &Superblock,
+  path: &str,
+  options: LookupOptions,
+) -> Result<Inode> {
+  let root = get_inode(r, sb, sb.root_inode_ref)?;
+  let mut current = (sb.root_inode_ref, root.clone());
+  // the inode references of the walked directories, by inode number
+  let mut walked = HashMap::new();
+  walked.insert(root.header().inode_number, sb.root_inode_ref);
+  let mut components: VecDeque<Vec<u8>> = split_path(path.as_bytes()).collect();
+  let must_be_dir = path.ends_with('/');
+  let mut follows = 0;
+
+  while let Some(name) = components.pop_front() {
+    if name.is_empty() || name == b"." {
+      continue;
+    }
+    let dir = current
+      .1
+      .as_directory()
+      .ok_or_else(|| not_a_directory_error!(path))?;
+    if name == b".." {
+      if current.0 != sb.root_inode_ref {
+        let parent = dir.parent_inode();
+        let parent_ref = *walked.get(&parent).ok_or_else(|| {
+          invalid_error!(format!(
+            "parent inode {} of inode {} is not a walked directory",
+            parent,
+            current.1.header().inode_number
+          ))
+        })?;
+        current = (parent_ref, get_inode(r, sb, parent_ref)?);
+      }
+      continue;
+    }
+
+    let entry = find_entry(r, sb, dir, &name)?.ok_or_else(|| not_found_error!(path))?;
+    let inode = get_inode(r, sb, entry.inode_ref)?;
+    trace!(
+      "[lookup] path={}, name={}, inode={:?}",
+      path,
+      entry.name_lossy(),
+      inode
+    );
+
+    let last = components.iter().all(|c| c.is_empty() || c == b".");
+    if let Some(target) = inode.symlink_target() {
+      if !last || must_be_dir || options.follow_symlinks {
+        follows += 1;
+        if follows > MAX_SYMLINK_FOLLOWS {
+          return Err(SquashfsError::SymlinkLoop(path.to_string()).into());
+        }
+        if target.starts_with(b"/") {
+          current = (sb.root_inode_ref, root.clone());
+        }
+        for component in split_path(target).collect::<Vec<_>>().into_iter().rev() {
+          components.push_front(component);
+        }
+        continue;
+      }
+    }
+
+    if inode.is_dir() {
+      walked.insert(inode.header().inode_number, entry.inode_ref);
+    }
+    current = (entry.inode_ref, inode);
+  }
+
+  let inode = current.1;
+  if must_be_dir && !inode.is_dir() {
+    return Err(not_a_directory_error!(path));
+  }
+  Ok(inode)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::tests::*;
+  use crate::*;
+  use byteorder::{ByteOrder, LittleEndian};
+  use std::io::{Cursor, ErrorKind, Result};
+
+  #[test]
+  fn test_lookup() -> Result<()> {
+    let mut cursor = Cursor::new(build_test_image());
+    let mut sb = Superblock::new();
+    sb.load(&mut cursor)?;
+    let nofollow = LookupOptions {
+      follow_symlinks: false,
+    };
+
+    let number = |r: &mut Cursor<Vec<u8>>, path: &str, options| -> Result<u32> {
+      Ok(lookup(r, &sb, path, options)?.header().inode_number)
+    };
+    let c = &mut cursor;
+
+    assert_eq!(number(c, "/", Default::default())?, 5);
+    assert_eq!(number(c, "sub/deep", Default::default())?, 3);
+    assert_eq!(number(c, "/link", Default::default())?, 1);
+    assert_eq!(number(c, "/link", nofollow)?, 2);
+    assert_eq!(number(c, "/sub/../link", nofollow)?, 2);
+    assert_eq!(number(c, "/../../sub/./deep", Default::default())?, 3);
+    assert_eq!(number(c, "/sub/..", Default::default())?, 5);
+    assert_eq!(number(c, "/sub/../sub/../sub", Default::default())?, 4);
+
+    let kind = |r: &mut Cursor<Vec<u8>>, path: &str| {
+      lookup(r, &sb, path, Default::default()).unwrap_err().kind()
+    };
+    assert_eq!(kind(c, "/sub/nothing"), ErrorKind::NotFound);
+    assert_eq!(kind(c, "/hello/deep"), ErrorKind::NotADirectory);
+    assert_eq!(kind(c, "/link/"), ErrorKind::NotADirectory);
+    assert_eq!(kind(c, "/hello/.."), ErrorKind::NotADirectory);
+    assert_eq!(kind(c, "/loop"), ErrorKind::Other);
+    assert!(lookup(c, &sb, "/loop", nofollow).is_ok());
+
+    // `..` of /sub goes to its parent_inode, point it at /hello instead of the root
+    let root = get_inode(c, &sb, sb.root_inode_ref)?;
+    let sub = find_entry(c, &sb, root.as_directory().unwrap(), b"sub")?.unwrap();
+    let parent = sb.inode_table_start as usize + 2 + sub.inode_ref.offset() as usize + 28;
+    let mut image = cursor.into_inner();
+    LittleEndian::write_u32(&mut image[parent..parent + 4], 1);
+    let c = &mut Cursor::new(image);
+    assert_eq!(number(c, "/sub/deep", Default::default())?, 3);
+    assert_eq!(
+      lookup(c, &sb, "/sub/..", Default::default()).unwrap_err().kind(),
+      ErrorKind::InvalidData
+    );
+
+    Ok(())
+  }
+}
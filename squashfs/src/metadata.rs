@@ -1,11 +1,15 @@
 use super::*;
+use crate::compress::{compress_block, get_compressor, Compressor, CompressorOptions};
 use byteorder::{ByteOrder, LittleEndian};
 use std::io::{Result, SeekFrom};
+use std::sync::Arc;
 
 pub const METADATA_BLOCK_SIZE: usize = 8192;
+/// Set in the header of a metadata block stored uncompressed.
+pub const METADATA_UNCOMPRESSED_FLAG: u16 = 0x8000;
 
 pub fn read_metadata(
-  r: &mut SqsIoReader,
+  r: &mut dyn SqsIoRead,
   algorithm: compress::Algorithm,
   first_block: u64,
   block_offset: u32,
@@ -16,8 +20,15 @@ pub fn read_metadata(
   let mut location = first_block + block_offset as u64;
 
   // read first block
-  let (meta, next_block_offset) = read_meta_block(r, algorithm, location as u64)?;
-  location = next_block_offset as u64;
+  let (meta, next_block_offset) = read_meta_block(r, algorithm, location)?;
+  location += next_block_offset as u64;
+  if byte_offset as usize > meta.len() {
+    return Err(invalid_error!(format!(
+      "metadata offset {} out of block size {}",
+      byte_offset,
+      meta.len()
+    )));
+  }
   buf.extend(&meta[(byte_offset as usize)..]);
 
   // maybe cross many block, read them all.
@@ -29,8 +40,8 @@ pub fn read_metadata(
       location,
       buf.len()
     );
-    let (meta, next_block_offset) = read_meta_block(r, algorithm, location as u64)?;
-    location = next_block_offset as u64;
+    let (meta, next_block_offset) = read_meta_block(r, algorithm, location)?;
+    location += next_block_offset as u64;
     buf.extend(meta);
     i += 1;
   }
@@ -39,7 +50,7 @@ pub fn read_metadata(
 }
 
 pub fn read_meta_block(
-  r: &mut SqsIoReader,
+  r: &mut dyn SqsIoRead,
   algorithm: compress::Algorithm,
   location: u64,
 ) -> Result<(Vec<u8>, u16)> {
@@ -50,6 +61,13 @@ pub fn read_meta_block(
 
   let header = LittleEndian::read_u16(&header_bytes);
   let (size, compressed) = get_metadata_size(header);
+  let corrupt = |source| SquashfsError::CorruptMetadata {
+    offset: location,
+    source,
+  };
+  if size as usize > METADATA_BLOCK_SIZE {
+    return Err(corrupt(invalid_error!(format!("block size {}", size))).into());
+  }
 
   debug!(
     "[read_meta_block] metadata: location={}, header={:?}/{:?} size={}, compressed={}",
@@ -69,7 +87,12 @@ pub fn read_meta_block(
 
   let mut output = vec![0u8; METADATA_BLOCK_SIZE];
   if compressed {
-    let desize = compress::decompress(&mut buf, &mut output, algorithm)?;
+    let desize = compress::decompress(&buf, &mut output, algorithm).map_err(|e| {
+      match SquashfsError::from(e) {
+        e @ SquashfsError::UnsupportedCompressor(_) => e,
+        e => corrupt(e.into()),
+      }
+    })?;
     let (temp, _) = output.split_at(desize);
     output = temp.to_vec();
   } else {
@@ -87,11 +110,89 @@ pub fn read_meta_block(
 
 /// returns data size and is compresseds
 pub fn get_metadata_size(header: u16) -> (u16, bool) {
-  let data_size = header & 0x7FFF;
-  let compressed = !(header & 0x8000 == 0x8000);
+  let data_size = header & !METADATA_UNCOMPRESSED_FLAG;
+  let compressed = header & METADATA_UNCOMPRESSED_FLAG != METADATA_UNCOMPRESSED_FLAG;
   (data_size, compressed)
 }
 
+/// Packs a table into metadata blocks, the inverse of `read_metadata`.
+///
+/// Each full block of 8 KiB is compressed, it is stored uncompressed with
+/// `METADATA_UNCOMPRESSED_FLAG` set in its header when compression doesn't make it smaller.
+pub struct MetadataWriter {
+  compressor: Arc<dyn Compressor>,
+  options: Option<CompressorOptions>,
+  /// the finished blocks, with their headers
+  blocks: Vec<u8>,
+  /// the uncompressed content of the current block
+  block: Vec<u8>,
+}
+
+impl MetadataWriter {
+  /// A writer compressing with the registered compressor of `algorithm`.
+  pub fn new(algorithm: compress::Algorithm, options: Option<CompressorOptions>) -> Result<Self> {
+    Ok(Self::with_compressor(get_compressor(algorithm)?, options))
+  }
+
+  pub fn with_compressor(
+    compressor: Arc<dyn Compressor>,
+    options: Option<CompressorOptions>,
+  ) -> Self {
+    MetadataWriter {
+      compressor,
+      options,
+      blocks: vec![],
+      block: Vec::with_capacity(METADATA_BLOCK_SIZE),
+    }
+  }
+
+  /// The reference of the next byte written: `block()` is the offset of its block
+  /// relative to the start of the table, `offset()` its offset in the uncompressed block.
+  /// Inodes and directory listings are referenced this way.
+  pub fn position(&self) -> InodeRef {
+    InodeRef::new(self.blocks.len() as u32, self.block.len() as u16)
+  }
+
+  pub fn write(&mut self, mut data: &[u8]) -> Result<()> {
+    while !data.is_empty() {
+      let size = (METADATA_BLOCK_SIZE - self.block.len()).min(data.len());
+      self.block.extend(&data[..size]);
+      data = &data[size..];
+      if self.block.len() == METADATA_BLOCK_SIZE {
+        self.flush()?;
+      }
+    }
+    Ok(())
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    if self.block.is_empty() {
+      return Ok(());
+    }
+    let (data, compressed) =
+      compress_block(self.compressor.as_ref(), self.options.as_ref(), &self.block)?;
+    let mut header = data.len() as u16;
+    if !compressed {
+      header |= METADATA_UNCOMPRESSED_FLAG;
+    }
+    trace!(
+      "[MetadataWriter::flush] size={}, compressed={}",
+      data.len(),
+      compressed
+    );
+    self.blocks.extend(&header.to_le_bytes());
+    self.blocks.extend(data.iter());
+    self.block.clear();
+    Ok(())
+  }
+
+  /// Write the last, partial block and return the blocks with their headers.
+  pub fn finish(mut self) -> Result<Vec<u8>> {
+    self.flush()?;
+    Ok(self.blocks)
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -117,6 +218,63 @@ mod tests {
     Ok(())
   }
 
+  #[test]
+  fn test_read_corrupt_meta_block() {
+    let mut image = std::io::Cursor::new(vec![0x0, 0x0, 0xff, 0x7f, 0x1, 0x2]);
+    let err = read_meta_block(&mut image, compress::Algorithm::Gzip, 2).unwrap_err();
+    assert!(matches!(
+      SquashfsError::from(err),
+      SquashfsError::CorruptMetadata { offset: 2, .. }
+    ));
+  }
+
+  #[test]
+  #[cfg(feature = "gzip")]
+  fn test_metadata_writer() -> Result<()> {
+    let algorithm = compress::Algorithm::Gzip;
+    let mut writer = MetadataWriter::new(algorithm, None)?;
+    let text = b"squashfs metadata ".repeat(200);
+    writer.write(&text)?;
+    // a pseudo random block gzip can't shrink
+    let mut state = 1u32;
+    let noise: Vec<u8> = (0..METADATA_BLOCK_SIZE)
+      .map(|_| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (state >> 16) as u8
+      })
+      .collect();
+    let noise_ref = writer.position();
+    assert_eq!(noise_ref.block(), 0);
+    assert_eq!(noise_ref.offset() as usize, text.len());
+    writer.write(&noise)?;
+    let end_ref = writer.position();
+    let blocks = writer.finish()?;
+
+    // the first block is compressed, the end of the noise is stored as is
+    let (first, compressed) = get_metadata_size(LittleEndian::read_u16(&blocks));
+    assert!(compressed);
+    assert_eq!(end_ref.block(), first as u32 + 2);
+    assert_eq!(end_ref.offset() as usize, text.len());
+    let second = LittleEndian::read_u16(&blocks[first as usize + 2..]);
+    assert_eq!(get_metadata_size(second), (text.len() as u16, false));
+
+    let mut image = std::io::Cursor::new(blocks);
+    let read = read_metadata(&mut image, algorithm, 0, 0, 0, text.len() + noise.len())?;
+    assert_eq!(&read[..text.len()], &text[..]);
+    assert_eq!(&read[text.len()..text.len() + noise.len()], &noise[..]);
+    let read = read_metadata(
+      &mut image,
+      algorithm,
+      0,
+      noise_ref.block(),
+      noise_ref.offset() as u32,
+      10,
+    )?;
+    assert_eq!(&read[..10], &noise[..10]);
+
+    Ok(())
+  }
+
   #[test]
   fn test_read_metad_block() -> Result<()> {
     let (mut reader, sb) = prepare_tests()?;
@@ -1,6 +1,6 @@
 use super::*;
 use byteorder::{ByteOrder, LittleEndian};
-use std::io::{Read, Result, SeekFrom};
+use std::io::{Result, SeekFrom};
 
 const ID_ENTRY_SIZE: usize = 4;
 type IdTab = Vec<u32>;
@@ -12,7 +12,7 @@ type IdTab = Vec<u32>;
 /// 3. Calculate meta blocks number;
 /// 4. Read the indexs, they are uncompressed, one index per metablock of the table, 8 bytes each(u64);
 /// 5. Read the table.
-pub fn read_lookup_table(r: &mut SqsIoReader, sb: Superblock) -> Result<IdTab> {
+pub fn read_lookup_table(r: &mut dyn SqsIoRead, sb: &Superblock) -> Result<IdTab> {
   if sb.id_count == 0 {
     return Ok(vec![]);
   }
@@ -39,19 +39,24 @@ pub fn read_lookup_table(r: &mut SqsIoReader, sb: Superblock) -> Result<IdTab> {
     data.extend(uncompressed.iter());
   }
 
-  Ok(parse_id_tab(&mut &*data)?)
-}
-
-pub fn parse_id_tab(raw: &mut &[u8]) -> Result<IdTab> {
-  let count = raw.len() / 4;
-  let mut entries = IdTab::with_capacity(count);
-  unsafe {
-    entries.set_len(count);
+  let mut entries = parse_id_tab(&data)?;
+  if entries.len() < sb.id_count as usize {
+    return Err(invalid_error!(format!(
+      "id table has {} entries, expected {}",
+      entries.len(),
+      sb.id_count
+    )));
   }
+  entries.truncate(sb.id_count as usize);
 
-  let (_, mut data, _) = unsafe { (&mut entries).align_to_mut::<u8>() };
+  Ok(entries)
+}
 
-  raw.read_exact(&mut data)?;
+pub fn parse_id_tab(raw: &[u8]) -> Result<IdTab> {
+  let entries: IdTab = raw
+    .chunks_exact(ID_ENTRY_SIZE)
+    .map(LittleEndian::read_u32)
+    .collect();
 
   trace!("[parse_id_tab] entries={:?}", entries);
 
@@ -68,7 +73,7 @@ mod tests {
   #[cfg_attr(not(feature = "gzip-sqs"), ignore)]
   fn test_lookup_table() -> Result<()> {
     let (mut reader, sb) = prepare_tests()?;
-    read_lookup_table(&mut reader, sb)?;
+    read_lookup_table(&mut reader, &sb)?;
     Ok(())
   }
 
@@ -80,7 +85,7 @@ mod tests {
 
     let expected = vec![0, 10, 0x2c020001, 0x7f852ae6];
 
-    let uidsgids = parse_id_tab(&mut &*raw)?;
+    let uidsgids = parse_id_tab(&raw)?;
 
     assert_eq!(uidsgids.len(), expected.len());
     assert_eq!(&uidsgids, &expected);
@@ -1,16 +1,142 @@
-/// map_other_error: convert other error to io:Error
-/// and ad file and line info to it.
+use crate::compress::Algorithm;
+use crate::layout::SuperblockError;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+/// The errors of this crate.
+///
+/// Functions return `io::Error`s carrying a `SquashfsError`, convert them back with
+/// `SquashfsError::from` to tell the failures apart.
+#[derive(Debug)]
+pub enum SquashfsError {
+  /// Reading or seeking the archive failed
+  Io(io::Error),
+  /// Not a squashfs archive
+  BadMagic(u32),
+  /// Any other superblock which can't belong to a valid archive
+  InvalidSuperblock(SuperblockError),
+  /// No compressor is compiled in or registered for the algorithm
+  UnsupportedCompressor(Algorithm),
+  /// A valid archive using a feature which is not implemented
+  Unsupported(String),
+  /// The metadata block at `offset` can't be read or decompressed
+  CorruptMetadata { offset: u64, source: io::Error },
+  /// Any other inconsistent on-disk data
+  Corrupt(String),
+  NotFound(String),
+  NotADirectory(String),
+  /// The content of something else than a regular file was requested
+  NotAFile(String),
+  /// Too many symbolic links were followed resolving the path
+  SymlinkLoop(String),
+  /// A failure reported by another library, e.g. a compressor
+  Other(Box<dyn Error + Send + Sync>),
+}
+
+impl SquashfsError {
+  /// The `io::ErrorKind` of the error once converted to an `io::Error`.
+  pub fn kind(&self) -> ErrorKind {
+    match self {
+      SquashfsError::Io(e) => e.kind(),
+      SquashfsError::BadMagic(_)
+      | SquashfsError::InvalidSuperblock(_)
+      | SquashfsError::CorruptMetadata { .. }
+      | SquashfsError::Corrupt(_) => ErrorKind::InvalidData,
+      SquashfsError::UnsupportedCompressor(_) | SquashfsError::Unsupported(_) => {
+        ErrorKind::Unsupported
+      }
+      SquashfsError::NotFound(_) => ErrorKind::NotFound,
+      SquashfsError::NotADirectory(_) => ErrorKind::NotADirectory,
+      SquashfsError::NotAFile(_) => ErrorKind::InvalidInput,
+      SquashfsError::SymlinkLoop(_) | SquashfsError::Other(_) => ErrorKind::Other,
+    }
+  }
+}
+
+impl fmt::Display for SquashfsError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      SquashfsError::Io(e) => write!(f, "{}", e),
+      SquashfsError::BadMagic(magic) => write!(f, "bad magic {:#x}, not a squashfs archive", magic),
+      SquashfsError::InvalidSuperblock(e) => write!(f, "invalid superblock: {}", e),
+      SquashfsError::UnsupportedCompressor(algorithm) => write!(
+        f,
+        "{} compressor is not supported, enable its feature or register one",
+        algorithm
+      ),
+      SquashfsError::Unsupported(msg) => write!(f, "{}", msg),
+      SquashfsError::CorruptMetadata { offset, .. } => {
+        write!(f, "corrupt metadata block at {}", offset)
+      }
+      SquashfsError::Corrupt(msg) => write!(f, "corrupt archive: {}", msg),
+      SquashfsError::NotFound(path) => write!(f, "{} not found", path),
+      SquashfsError::NotADirectory(path) => write!(f, "{} is not a directory", path),
+      SquashfsError::NotAFile(path) => write!(f, "{} is not a regular file", path),
+      SquashfsError::SymlinkLoop(path) => {
+        write!(f, "too many levels of symbolic links in {}", path)
+      }
+      SquashfsError::Other(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl Error for SquashfsError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      SquashfsError::Io(e) => Some(e),
+      SquashfsError::InvalidSuperblock(e) => Some(e),
+      SquashfsError::CorruptMetadata { source, .. } => Some(source),
+      SquashfsError::Other(e) => Some(e.as_ref()),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for SquashfsError {
+  /// Take back the `SquashfsError` carried by `e`, other errors become `Io`.
+  fn from(e: io::Error) -> Self {
+    if !e.get_ref().is_some_and(|inner| inner.is::<SquashfsError>()) {
+      return SquashfsError::Io(e);
+    }
+    match e.into_inner().map(|inner| inner.downcast::<SquashfsError>()) {
+      Some(Ok(inner)) => *inner,
+      _ => unreachable!("checked above"),
+    }
+  }
+}
+
+impl From<SquashfsError> for io::Error {
+  fn from(e: SquashfsError) -> Self {
+    match e {
+      SquashfsError::Io(e) => e,
+      e => io::Error::new(e.kind(), e),
+    }
+  }
+}
+
+impl From<SuperblockError> for SquashfsError {
+  fn from(e: SuperblockError) -> Self {
+    match e {
+      SuperblockError::BadMagic(magic) => SquashfsError::BadMagic(magic),
+      e => SquashfsError::InvalidSuperblock(e),
+    }
+  }
+}
+
+/// map_other_error: convert the error of another library to io:Error.
 #[macro_export]
 macro_rules! map_other_error {
   ($E: expr) => {
-    std::io::Error::new(
-      std::io::ErrorKind::Other,
-      format!("Error {} at {}:{}", $E, file!(), line!()),
-    )
+    std::io::Error::from($crate::SquashfsError::Other($E.into()))
   };
 }
 
 /// map_error: add file and line info to io::Error;
+///
+/// The message replaces a `SquashfsError` carried by the error, so callers can't match
+/// on it anymore. Propagate the error unchanged instead.
+#[deprecated(note = "propagate the io::Error unchanged, it may carry a SquashfsError")]
 #[macro_export]
 macro_rules! map_error {
   ($E: expr) => {
@@ -25,9 +151,76 @@ macro_rules! map_error {
 #[macro_export]
 macro_rules! invalid_error {
   ($msg:expr) => {{
-    std::io::Error::new(
-      std::io::ErrorKind::InvalidData,
-      format!("Error {} at {}:{}", $msg, file!(), line!()),
-    )
+    std::io::Error::from($crate::SquashfsError::Corrupt(format!("{}", $msg)))
   }};
 }
+
+/// Path not found error
+#[macro_export]
+macro_rules! not_found_error {
+  ($path:expr) => {{
+    std::io::Error::from($crate::SquashfsError::NotFound(format!("{}", $path)))
+  }};
+}
+
+/// A path component is not a directory
+#[macro_export]
+macro_rules! not_a_directory_error {
+  ($path:expr) => {{
+    std::io::Error::from($crate::SquashfsError::NotADirectory(format!("{}", $path)))
+  }};
+}
+
+/// Not a regular file
+#[macro_export]
+macro_rules! not_a_file_error {
+  ($path:expr) => {{
+    std::io::Error::from($crate::SquashfsError::NotAFile(format!("{}", $path)))
+  }};
+}
+
+/// Unsupported feature, e.g. a table this crate can't read
+#[macro_export]
+macro_rules! unsupported_error {
+  ($msg:expr) => {{
+    std::io::Error::from($crate::SquashfsError::Unsupported(format!("{}", $msg)))
+  }};
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_io_error_round_trip() {
+    let e = io::Error::from(SquashfsError::CorruptMetadata {
+      offset: 96,
+      source: invalid_error!("bad header"),
+    });
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    let e = SquashfsError::from(e);
+    assert!(matches!(e, SquashfsError::CorruptMetadata { offset: 96, .. }));
+    let source = e.source().unwrap().downcast_ref::<io::Error>().unwrap();
+    assert!(matches!(
+      source.get_ref().unwrap().downcast_ref::<SquashfsError>(),
+      Some(SquashfsError::Corrupt(_))
+    ));
+
+    let e = SquashfsError::from(io::Error::from(ErrorKind::UnexpectedEof));
+    assert!(matches!(e, SquashfsError::Io(_)));
+    assert_eq!(io::Error::from(e).kind(), ErrorKind::UnexpectedEof);
+
+    assert!(matches!(
+      SquashfsError::from(SuperblockError::BadMagic(0)),
+      SquashfsError::BadMagic(0)
+    ));
+  }
+
+  #[test]
+  #[allow(deprecated)]
+  fn test_map_error() {
+    let e = map_error!(not_found_error!("/etc"));
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+    assert!(e.to_string().starts_with("Error /etc not found at "));
+  }
+}
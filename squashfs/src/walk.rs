@@ -0,0 +1,164 @@
+use super::*;
+use std::collections::{HashSet, VecDeque};
+use std::io::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, SmartDefault)]
+pub enum WalkOrder {
+  /// Every directory is followed by its whole subtree.
+  #[default]
+  DepthFirst,
+  /// All entries of a level come before the entries of the next level.
+  BreadthFirst,
+}
+
+/// A node of the tree yielded by `Walk`.
+#[derive(Clone, Debug)]
+pub struct WalkEntry {
+  /// The absolute path of the node, the root is `/`
+  pub path: Vec<u8>,
+
+  /// The number of components in `path`, 0 for the root
+  pub depth: usize,
+
+  pub inode: Inode,
+}
+
+impl WalkEntry {
+  pub fn path_lossy(&self) -> String {
+    String::from_utf8_lossy(&self.path).into_owned()
+  }
+
+  pub fn header(&self) -> &InodeHeader {
+    self.inode.header()
+  }
+}
+
+/// Iterator over every node of an archive, starting with the root.
+///
+/// The children of a directory are listed when the iterator advances past it,
+/// so calling `skip_subtree` right after a directory was yielded prunes it.
+pub struct Walk<'a> {
+  r: &'a mut dyn SqsIoRead,
+  sb: &'a Superblock,
+  order: WalkOrder,
+  queue: VecDeque<(Vec<u8>, usize, InodeRef)>,
+  /// the last yielded directory, its children are not queued yet
+  pending: Option<WalkEntry>,
+  visited: HashSet<InodeRef>,
+}
+
+impl<'a> Walk<'a> {
+  pub fn new(r: &'a mut dyn SqsIoRead, sb: &'a Superblock, order: WalkOrder) -> Self {
+    let mut queue = VecDeque::new();
+    queue.push_back((b"/".to_vec(), 0, sb.root_inode_ref));
+    Walk {
+      r,
+      sb,
+      order,
+      queue,
+      pending: None,
+      visited: HashSet::new(),
+    }
+  }
+
+  /// Don't descend into the directory yielded last.
+  pub fn skip_subtree(&mut self) {
+    self.pending = None;
+  }
+
+  fn expand(&mut self, dir: WalkEntry) -> Result<()> {
+    let body = match dir.inode.as_directory() {
+      Some(body) => body,
+      None => return Ok(()),
+    };
+
+    let mut children = vec![];
+    for entry in read_directory(self.r, self.sb, body)? {
+      let entry = entry?;
+      let mut path = dir.path.clone();
+      if dir.depth > 0 {
+        path.push(b'/');
+      }
+      path.extend(&entry.name);
+      children.push((path, dir.depth + 1, entry.inode_ref));
+    }
+
+    match self.order {
+      WalkOrder::DepthFirst => {
+        for child in children.into_iter().rev() {
+          self.queue.push_front(child);
+        }
+      }
+      WalkOrder::BreadthFirst => self.queue.extend(children),
+    }
+    Ok(())
+  }
+}
+
+impl Iterator for Walk<'_> {
+  type Item = Result<WalkEntry>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if let Some(dir) = self.pending.take() {
+      if let Err(e) = self.expand(dir) {
+        return Some(Err(e));
+      }
+    }
+
+    let (path, depth, inode_ref) = self.queue.pop_front()?;
+    let inode = match get_inode(self.r, self.sb, inode_ref) {
+      Ok(inode) => inode,
+      Err(e) => return Some(Err(e)),
+    };
+    let entry = WalkEntry { path, depth, inode };
+
+    // a directory reached twice means a corrupt image, don't loop forever
+    if entry.inode.is_dir() && self.visited.insert(inode_ref) {
+      self.pending = Some(entry.clone());
+    }
+
+    Some(Ok(entry))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::tests::*;
+  use crate::*;
+  use std::io::{Cursor, Result};
+
+  fn paths(walk: Walk) -> Result<Vec<String>> {
+    walk.map(|e| e.map(|e| e.path_lossy())).collect()
+  }
+
+  #[test]
+  fn test_walk() -> Result<()> {
+    let mut cursor = Cursor::new(build_test_image());
+    let mut sb = Superblock::new();
+    sb.load(&mut cursor)?;
+
+    let dfs = paths(Walk::new(&mut cursor, &sb, WalkOrder::DepthFirst))?;
+    assert_eq!(
+      dfs,
+      vec!["/", "/hello", "/link", "/loop", "/sub", "/sub/deep"]
+    );
+
+    let bfs = Walk::new(&mut cursor, &sb, WalkOrder::BreadthFirst).collect::<Result<Vec<_>>>()?;
+    assert_eq!(bfs.len(), dfs.len());
+    assert!(bfs.windows(2).all(|w| w[0].depth <= w[1].depth));
+    assert_eq!(bfs.last().unwrap().header().inode_number, 3);
+
+    let mut walk = Walk::new(&mut cursor, &sb, WalkOrder::DepthFirst);
+    let mut pruned = vec![];
+    while let Some(entry) = walk.next() {
+      let entry = entry?;
+      if entry.path == b"/sub" {
+        walk.skip_subtree();
+      }
+      pruned.push(entry.path_lossy());
+    }
+    assert_eq!(pruned, vec!["/", "/hello", "/link", "/loop", "/sub"]);
+
+    Ok(())
+  }
+}
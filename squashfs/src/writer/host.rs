@@ -0,0 +1,146 @@
+use super::*;
+use std::fs::{self, File, Metadata};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+/// Split a device number of the host into major and minor, with the glibc encoding.
+fn host_major_minor(rdev: u64) -> (u32, u32) {
+  let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+  let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+  (major as u32, minor as u32)
+}
+
+/// The extended attributes of `path`, sorted by name. Filesystems without xattrs
+/// support have none, names outside the `user`, `trusted` and `security` namespaces
+/// can't be stored and are skipped.
+fn host_xattrs(path: &Path) -> Result<Vec<XAttr>> {
+  let names = match xattr::list(path) {
+    Ok(names) => names,
+    Err(e) if e.kind() == ErrorKind::Unsupported => return Ok(vec![]),
+    Err(e) => return Err(e),
+  };
+
+  let mut xattrs = vec![];
+  for name in names {
+    let name = name.as_bytes().to_vec();
+    if XAttrPrefix::split(&name).is_none() {
+      warn!(
+        "[host_xattrs] skip xattr {} of {}",
+        String::from_utf8_lossy(&name),
+        path.display()
+      );
+      continue;
+    }
+    if let Some(value) = xattr::get(path, std::ffi::OsStr::from_bytes(&name))? {
+      xattrs.push(XAttr { name, value });
+    }
+  }
+  xattrs.sort_by(|a, b| a.name.cmp(&b.name));
+  Ok(xattrs)
+}
+
+impl<W: Write + Seek> SquashfsWriter<W> {
+  /// Add the content of the host directory `path` to the root of the image, the root
+  /// takes the attributes of `path`. Hard links are stored as distinct files.
+  ///
+  /// Entries added before are kept, a directory of both is merged and takes the host
+  /// attributes. Any other entry of the host whose name is taken fails with
+  /// `ErrorKind::AlreadyExists`.
+  pub fn add_host_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+    let path = path.as_ref();
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+      return Err(not_a_directory_error!(path.display()));
+    }
+    let mut root = std::mem::replace(
+      &mut self.root,
+      Node::new(Attributes::default(), Content::Fifo),
+    );
+    let result = self.scan_host_dir(path, &metadata, &mut root);
+    self.root = root;
+    result
+  }
+
+  fn host_attributes(&self, path: &Path, metadata: &Metadata) -> Result<Attributes> {
+    Ok(Attributes {
+      permissions: (metadata.mode() & 0o7777) as u16,
+      uid: metadata.uid(),
+      gid: metadata.gid(),
+      modified_time: metadata.mtime().clamp(0, u32::MAX as i64) as u32,
+      xattrs: if self.options.xattrs {
+        host_xattrs(path)?
+      } else {
+        vec![]
+      },
+    })
+  }
+
+  fn scan_host_dir(&mut self, path: &Path, metadata: &Metadata, node: &mut Node) -> Result<()> {
+    node.attrs = self.host_attributes(path, metadata)?;
+    let mut entries = fs::read_dir(path)?.collect::<Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+      let path = entry.path();
+      let name = entry.file_name().as_bytes().to_vec();
+      check_name(&name, &path.display())?;
+      let is_dir = entry.file_type()?.is_dir();
+      let children = match &mut node.content {
+        Content::Directory(children) => children,
+        _ => return Err(not_a_directory_error!(path.display())),
+      };
+      match children.get_mut(&name) {
+        Some(child) if is_dir && matches!(child.content, Content::Directory(_)) => {
+          let metadata = fs::symlink_metadata(&path)?;
+          self.scan_host_dir(&path, &metadata, child)?;
+        }
+        Some(_) => return Err(already_exists(&path.display().to_string())),
+        None => {
+          let child = self.scan_host_entry(&path)?;
+          children.insert(name, child);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn scan_host_entry(&mut self, path: &Path) -> Result<Node> {
+    let metadata = fs::symlink_metadata(path)?;
+    trace!("[scan_host_entry] path={}", path.display());
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+      let mut node = Node::new(
+        Attributes::default(),
+        Content::Directory(BTreeMap::new()),
+      );
+      self.scan_host_dir(path, &metadata, &mut node)?;
+      return Ok(node);
+    }
+
+    let attrs = self.host_attributes(path, &metadata)?;
+    let content = if file_type.is_file() {
+      Content::File(self.write_file_data(&mut File::open(path)?)?)
+    } else if file_type.is_symlink() {
+      Content::Symlink(fs::read_link(path)?.as_os_str().as_bytes().to_vec())
+    } else if file_type.is_block_device() || file_type.is_char_device() {
+      let (major, minor) = host_major_minor(metadata.rdev());
+      let device = device_number(major, minor);
+      if file_type.is_block_device() {
+        Content::BlockDevice(device)
+      } else {
+        Content::CharDevice(device)
+      }
+    } else if file_type.is_fifo() {
+      Content::Fifo
+    } else if file_type.is_socket() {
+      Content::Socket
+    } else {
+      return Err(unsupported_error!(format!(
+        "unknown file type of {}",
+        path.display()
+      )));
+    };
+    Ok(Node::new(attrs, content))
+  }
+}
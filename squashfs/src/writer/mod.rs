@@ -0,0 +1,1412 @@
+use super::*;
+use crate::compress::{compress_block, get_compressor, Algorithm, Compressor, CompressorOptions};
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::Hasher;
+use std::io::{self, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(unix)]
+mod host;
+
+pub const DEFAULT_BLOCK_SIZE: u32 = 128 * 1024;
+/// Images are padded to a multiple of 4 KiB like mksquashfs does, to be usable as block devices.
+pub const PAD_SIZE: u64 = 4096;
+
+/// Options of `SquashfsWriter`, the defaults are the ones of mksquashfs.
+#[derive(Clone, Debug)]
+pub struct WriterOptions {
+  pub compressor: Algorithm,
+
+  /// The options of the compressor, stored after the superblock. `None` to use the defaults
+  pub compressor_options: Option<CompressorOptions>,
+
+  /// The size of a data block, a power of two between 4 KiB and 1 MiB
+  pub block_size: u32,
+
+  /// Write the export table, needed to export the image via NFS
+  pub exportable: bool,
+
+  /// Store extended attributes
+  pub xattrs: bool,
+
+  /// `Superblock::modification_time`, the current time if `None`
+  pub modification_time: Option<u32>,
+
+  /// Clamp the modification time of the image and of every inode to this time,
+  /// like `SOURCE_DATE_EPOCH`
+  pub clamp_time: Option<u32>,
+
+  /// Store this uid instead of the one of every inode
+  pub uid: Option<u32>,
+
+  /// Store this gid instead of the one of every inode
+  pub gid: Option<u32>,
+}
+
+impl Default for WriterOptions {
+  fn default() -> Self {
+    WriterOptions {
+      compressor: Algorithm::Gzip,
+      compressor_options: None,
+      block_size: DEFAULT_BLOCK_SIZE,
+      exportable: true,
+      xattrs: true,
+      modification_time: None,
+      clamp_time: None,
+      uid: None,
+      gid: None,
+    }
+  }
+}
+
+impl WriterOptions {
+  /// Options for byte-identical images from identical inputs: times are clamped to
+  /// `source_date_epoch` and all files are owned by root.
+  ///
+  /// Directory entries are always sorted by name and inodes numbered in that order.
+  /// `add_host_dir` adds the files in that order too, but the data of the files added
+  /// with the builder methods is written in the order of the calls: images built that
+  /// way are only identical if the calls are made in the same order.
+  pub fn reproducible(source_date_epoch: u32) -> Self {
+    WriterOptions {
+      modification_time: Some(source_date_epoch),
+      clamp_time: Some(source_date_epoch),
+      uid: Some(0),
+      gid: Some(0),
+      ..Default::default()
+    }
+  }
+}
+
+/// The `SOURCE_DATE_EPOCH` environment variable, `None` if it is unset or not a valid time.
+pub fn source_date_epoch() -> Option<u32> {
+  std::env::var("SOURCE_DATE_EPOCH").ok()?.trim().parse().ok()
+}
+
+/// The attributes of an entry added to the image, stored in its `InodeHeader`
+/// and the xattr table.
+#[derive(Clone, Debug, Default)]
+pub struct Attributes {
+  /// The mode without the file type, e.g. `0o644`
+  pub permissions: u16,
+  pub uid: u32,
+  pub gid: u32,
+  pub modified_time: u32,
+  /// Full names with the `user.`, `trusted.` or `security.` prefix
+  pub xattrs: Vec<XAttr>,
+}
+
+/// Where the content of a regular file was written.
+#[derive(Clone, Debug)]
+pub(crate) struct FileData {
+  pub size: u64,
+  pub blocks_start: u64,
+  pub block_sizes: Vec<u32>,
+  /// The number of bytes in sparse blocks
+  pub sparse: u64,
+  pub fragment_block_idx: u32,
+  pub fragment_offset: u32,
+}
+
+pub(crate) enum Content {
+  Directory(BTreeMap<Vec<u8>, Node>),
+  File(FileData),
+  Symlink(Vec<u8>),
+  BlockDevice(u32),
+  CharDevice(u32),
+  Fifo,
+  Socket,
+}
+
+pub(crate) struct Node {
+  pub attrs: Attributes,
+  pub content: Content,
+  /// Assigned when the writer is finished
+  inode_number: u32,
+}
+
+impl Node {
+  pub fn new(attrs: Attributes, content: Content) -> Self {
+    Node {
+      attrs,
+      content,
+      inode_number: 0,
+    }
+  }
+
+  /// The type listed in directory entries.
+  fn basic_type(&self) -> InodeType {
+    match self.content {
+      Content::Directory(_) => InodeType::BasicDirectory,
+      Content::File(_) => InodeType::BasicFile,
+      Content::Symlink(_) => InodeType::BasicSymlink,
+      Content::BlockDevice(_) => InodeType::BasicBlockDevice,
+      Content::CharDevice(_) => InodeType::BasicCharDevice,
+      Content::Fifo => InodeType::BasicFifo,
+      Content::Socket => InodeType::BasicSocket,
+    }
+  }
+
+  /// Number the inodes of the tree, the children of a directory come before it so
+  /// the root is the last one. Returns the last number.
+  fn number_inodes(&mut self, mut last: u32) -> u32 {
+    if let Content::Directory(children) = &mut self.content {
+      for child in children.values_mut() {
+        last = child.number_inodes(last);
+      }
+    }
+    self.inode_number = last + 1;
+    self.inode_number
+  }
+}
+
+fn clamp(time: u32, max: Option<u32>) -> u32 {
+  max.map_or(time, |max| time.min(max))
+}
+
+fn already_exists(path: &str) -> io::Error {
+  io::Error::new(ErrorKind::AlreadyExists, format!("{} already exists", path))
+}
+
+/// The inode, directory, id, export and xattr tables, filled while the tree is written.
+struct Tables {
+  inodes: MetadataWriter,
+  directories: MetadataWriter,
+  ids: Vec<u32>,
+  id_idx: HashMap<u32, u16>,
+  exports: Vec<InodeRef>,
+  xattrs: bool,
+  clamp_time: Option<u32>,
+  uid: Option<u32>,
+  gid: Option<u32>,
+  xattr_kv: MetadataWriter,
+  xattr_ids: Vec<XAttrIndex>,
+  xattr_idx: HashMap<Vec<XAttr>, u32>,
+}
+
+impl Tables {
+  fn id_idx(&mut self, id: u32) -> Result<u16> {
+    if let Some(idx) = self.id_idx.get(&id) {
+      return Ok(*idx);
+    }
+    if self.ids.len() >= u16::MAX as usize {
+      return Err(unsupported_error!("more than 65535 distinct uids and gids"));
+    }
+    let idx = self.ids.len() as u16;
+    self.ids.push(id);
+    self.id_idx.insert(id, idx);
+    Ok(idx)
+  }
+
+  /// The index of `xattrs` in the xattr id table, inodes with the same xattrs share it.
+  fn xattr_idx(&mut self, xattrs: &[XAttr]) -> Result<u32> {
+    if xattrs.is_empty() || !self.xattrs {
+      return Ok(NO_XATTR_INODE_FLAG);
+    }
+    if let Some(idx) = self.xattr_idx.get(xattrs) {
+      return Ok(*idx);
+    }
+    let (data, size) = write_xattr_pairs(xattrs)?;
+    let location = self.xattr_kv.position();
+    self.xattr_kv.write(&data)?;
+
+    let idx = self.xattr_ids.len() as u32;
+    self.xattr_ids.push(XAttrIndex {
+      location: location.0,
+      count: xattrs.len() as u32,
+      size,
+    });
+    self.xattr_idx.insert(xattrs.to_vec(), idx);
+    Ok(idx)
+  }
+
+  /// Write the inodes of the tree under `node` and the listings of its directories,
+  /// children first. Returns the reference of the inode of `node`.
+  fn write_node(&mut self, node: &Node, parent_inode: u32) -> Result<InodeRef> {
+    let xattr_idx = self.xattr_idx(&node.attrs.xattrs)?;
+    let extended = xattr_idx != NO_XATTR_INODE_FLAG;
+    let base = InodeHeader {
+      inode_type: node.basic_type(),
+      permissions: node.attrs.permissions,
+      uid_idx: self.id_idx(self.uid.unwrap_or(node.attrs.uid))?,
+      gid_idx: self.id_idx(self.gid.unwrap_or(node.attrs.gid))?,
+      modified_time: clamp(node.attrs.modified_time, self.clamp_time),
+      inode_number: node.inode_number,
+    };
+    let header = |inode_type| InodeHeader {
+      inode_type,
+      ..base.clone()
+    };
+
+    let inode = match &node.content {
+      Content::Directory(children) => {
+        let mut entries = Vec::with_capacity(children.len());
+        for (name, child) in children {
+          entries.push(DirEntry {
+            name: name.clone(),
+            inode_type: child.basic_type(),
+            inode_ref: self.write_node(child, node.inode_number)?,
+            inode_number: child.inode_number,
+          });
+        }
+        let location = self.directories.position();
+        let listing = write_directory(&entries);
+        self.directories.write(&listing)?;
+
+        let size = listing.len() as u32 + DIRECTORY_SIZE_OFFSET;
+        let nlink = 2 + entries.iter().filter(|e| e.is_dir()).count() as u32;
+        if extended || size > u16::MAX as u32 {
+          Inode::ExtendedDirectory(
+            header(InodeType::ExtendedDirectory),
+            ExtendedDirectory {
+              nlink,
+              size,
+              block_idx: location.block(),
+              parent_inode,
+              inodex_count: 0,
+              offset: location.offset(),
+              xattr_idx,
+              index: vec![],
+            },
+          )
+        } else {
+          Inode::BasicDirectory(
+            header(InodeType::BasicDirectory),
+            BasicDirectory {
+              block_idx: location.block(),
+              nlink,
+              size: size as u16,
+              offset: location.offset(),
+              parent_inode,
+            },
+          )
+        }
+      }
+      Content::File(file) => {
+        if extended
+          || file.sparse > 0
+          || file.size > u32::MAX as u64
+          || file.blocks_start > u32::MAX as u64
+        {
+          Inode::ExtendedFile(
+            header(InodeType::ExtendedFile),
+            ExtendedFile {
+              block_idx: file.blocks_start,
+              size: file.size,
+              sparse: file.sparse,
+              nlink: 1,
+              fragment_block_idx: file.fragment_block_idx,
+              offset: file.fragment_offset,
+              xattr_idx,
+              block_sizes: file.block_sizes.clone(),
+            },
+          )
+        } else {
+          Inode::BasicFile(
+            header(InodeType::BasicFile),
+            BasicFile {
+              block_idx: file.blocks_start as u32,
+              fragment_block_idx: file.fragment_block_idx,
+              offset: file.fragment_offset,
+              size: file.size as u32,
+              block_sizes: file.block_sizes.clone(),
+            },
+          )
+        }
+      }
+      Content::Symlink(target) => {
+        if extended {
+          Inode::ExtendedSymlink(
+            header(InodeType::ExtendedSymlink),
+            ExtendedSymlink {
+              nlink: 1,
+              target_size: target.len() as u32,
+              target_path: target.clone(),
+              xattr_idx,
+            },
+          )
+        } else {
+          Inode::BasicSymlink(
+            header(InodeType::BasicSymlink),
+            BasicSymlink {
+              nlink: 1,
+              target_size: target.len() as u32,
+              target_path: target.clone(),
+            },
+          )
+        }
+      }
+      Content::BlockDevice(device) => {
+        if extended {
+          Inode::ExtendedBlockDevice(
+            header(InodeType::ExtendedBlockDevice),
+            ExtendedBlock {
+              nlink: 1,
+              device: *device,
+              xattr_idx,
+            },
+          )
+        } else {
+          Inode::BasicBlockDevice(
+            header(InodeType::BasicBlockDevice),
+            BasicBlockDevice {
+              nlink: 1,
+              device: *device,
+            },
+          )
+        }
+      }
+      Content::CharDevice(device) => {
+        if extended {
+          Inode::ExtendedCharDevice(
+            header(InodeType::ExtendedCharDevice),
+            ExtendedChar {
+              nlink: 1,
+              device: *device,
+              xattr_idx,
+            },
+          )
+        } else {
+          Inode::BasicCharDevice(
+            header(InodeType::BasicCharDevice),
+            BasicCharDevice {
+              nlink: 1,
+              device: *device,
+            },
+          )
+        }
+      }
+      Content::Fifo => {
+        if extended {
+          Inode::ExtendedFifo(
+            header(InodeType::ExtendedFifo),
+            ExtendedFifo { nlink: 1, xattr_idx },
+          )
+        } else {
+          Inode::BasicFifo(header(InodeType::BasicFifo), BasicFifo { nlink: 1 })
+        }
+      }
+      Content::Socket => {
+        if extended {
+          Inode::ExtendedSocket(
+            header(InodeType::ExtendedSocket),
+            ExtendedSocket { nlink: 1, xattr_idx },
+          )
+        } else {
+          Inode::BasicSocket(header(InodeType::BasicSocket), BasicSocket { nlink: 1 })
+        }
+      }
+    };
+
+    let inode_ref = self.inodes.position();
+    self.inodes.write(&inode.to_bytes())?;
+    self.exports[node.inode_number as usize - 1] = inode_ref;
+    trace!("[write_node] inode={:?}, ref={}", inode, inode_ref);
+    Ok(inode_ref)
+  }
+}
+
+/// The names of the components of `path`, `.` and empty components are skipped.
+fn path_components(path: &str) -> Result<Vec<&[u8]>> {
+  let mut components = vec![];
+  for name in path.as_bytes().split(|c| *c == b'/') {
+    if name.is_empty() || name == b"." {
+      continue;
+    }
+    if name == b".." {
+      return Err(io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("{} must not contain ..", path),
+      ));
+    }
+    check_name(name, &path)?;
+    components.push(name);
+  }
+  Ok(components)
+}
+
+/// Names of directory entries are neither empty nor longer than `DIRECTORY_MAX_NAME_LEN`.
+fn check_name(name: &[u8], path: &dyn fmt::Display) -> Result<()> {
+  if name.is_empty() || name.len() > DIRECTORY_MAX_NAME_LEN {
+    return Err(io::Error::new(
+      ErrorKind::InvalidInput,
+      format!(
+        "{} has a name of {} bytes, it must have 1 to {}",
+        path,
+        name.len(),
+        DIRECTORY_MAX_NAME_LEN
+      ),
+    ));
+  }
+  Ok(())
+}
+
+/// The children of the directory at `components` under `node`.
+fn children_mut<'a>(
+  node: &'a mut Node,
+  components: &[&[u8]],
+  path: &str,
+) -> Result<&'a mut BTreeMap<Vec<u8>, Node>> {
+  let mut node = node;
+  for name in components {
+    node = match &mut node.content {
+      Content::Directory(children) => children
+        .get_mut(*name)
+        .ok_or_else(|| not_found_error!(path))?,
+      _ => return Err(not_a_directory_error!(path)),
+    };
+  }
+  match &mut node.content {
+    Content::Directory(children) => Ok(children),
+    _ => Err(not_a_directory_error!(path)),
+  }
+}
+
+/// read until `block` is full or the end of the input.
+fn read_block(r: &mut dyn Read, block: &mut [u8]) -> Result<usize> {
+  let mut size = 0;
+  while size < block.len() {
+    match r.read(&mut block[size..]) {
+      Ok(0) => break,
+      Ok(n) => size += n,
+      Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(size)
+}
+
+/// Reads the data written at an offset of the output.
+type ReadBack<W> = fn(&mut W, u64, &mut [u8]) -> Result<()>;
+
+/// Builds a squashfs image into any seekable writer, like mksquashfs.
+///
+/// The content of the files is written as soon as they are added, files smaller than a
+/// block are packed into fragment blocks. The inode, directory, fragment, export, id
+/// and xattr tables and the superblock are written by `finish`.
+pub struct SquashfsWriter<W: Write + Seek> {
+  out: W,
+  /// where the image starts in `out`
+  start: u64,
+  /// the end of the data written so far, relative to `start`
+  pos: u64,
+  options: WriterOptions,
+  compressor: Arc<dyn Compressor>,
+  root: Node,
+  fragments: Vec<FragmentEntry>,
+  /// the tail ends waiting for the next fragment block
+  fragment_block: Vec<u8>,
+  /// the end of the data written before rewinding over duplicates
+  end: u64,
+  /// reads back written data to compare duplicates, set if deduplication is enabled
+  read_back: Option<ReadBack<W>>,
+  /// the files written, by size and hash of their content
+  duplicates: HashMap<(u64, u64), Vec<FileData>>,
+}
+
+impl<W: Write + Seek> SquashfsWriter<W> {
+  /// Start an image at the current position of `out` with the default options.
+  pub fn new(out: W) -> Result<Self> {
+    Self::with_options(out, WriterOptions::default())
+  }
+
+  pub fn with_options(mut out: W, mut options: WriterOptions) -> Result<Self> {
+    if !options.block_size.is_power_of_two()
+      || options.block_size < MIN_BLOCK_SIZE
+      || options.block_size > MAX_BLOCK_SIZE
+    {
+      return Err(io::Error::new(
+        ErrorKind::InvalidInput,
+        format!(
+          "block size {} is not a power of two between {} and {}",
+          options.block_size, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE
+        ),
+      ));
+    }
+    let compressor = get_compressor(options.compressor)?;
+    let modification_time = options.modification_time.unwrap_or_else(|| {
+      let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+      now.as_secs().min(u32::MAX as u64) as u32
+    });
+    options.modification_time = Some(modification_time);
+
+    let start = out.stream_position()?;
+    let root = Node::new(
+      Attributes {
+        permissions: 0o755,
+        modified_time: modification_time,
+        ..Default::default()
+      },
+      Content::Directory(BTreeMap::new()),
+    );
+    let mut writer = SquashfsWriter {
+      out,
+      start,
+      pos: 0,
+      options,
+      compressor,
+      root,
+      fragments: vec![],
+      fragment_block: vec![],
+      end: 0,
+      read_back: None,
+      duplicates: HashMap::new(),
+    };
+
+    // the superblock is written by `finish`, the compressor options follow it
+    writer.write_all(&[0u8; SUPERBLOCK_SIZE])?;
+    if let Some(options) = &writer.options.compressor_options {
+      let data = writer.compressor.serialize_options(options)?;
+      let header = data.len() as u16 | METADATA_UNCOMPRESSED_FLAG;
+      writer.write_all(&header.to_le_bytes())?;
+      writer.write_all(&data)?;
+    }
+
+    Ok(writer)
+  }
+
+  /// Add a directory, its parent must already exist. Adding an existing directory,
+  /// e.g. `/`, replaces its attributes.
+  pub fn add_dir(&mut self, path: &str, attrs: Attributes) -> Result<()> {
+    let components = path_components(path)?;
+    let (name, parent) = match components.split_last() {
+      Some(split) => split,
+      None => {
+        self.root.attrs = attrs;
+        return Ok(());
+      }
+    };
+    let children = children_mut(&mut self.root, parent, path)?;
+    match children.get_mut(*name) {
+      Some(node) if matches!(node.content, Content::Directory(_)) => node.attrs = attrs,
+      Some(_) => return Err(already_exists(path)),
+      None => {
+        children.insert(
+          name.to_vec(),
+          Node::new(attrs, Content::Directory(BTreeMap::new())),
+        );
+      }
+    }
+    Ok(())
+  }
+
+  /// Add a regular file with the content of `reader`, which is written right away.
+  pub fn add_file(&mut self, path: &str, attrs: Attributes, reader: &mut dyn Read) -> Result<()> {
+    // check the path before writing the data
+    self.insert_node(path, None)?;
+    let file = self.write_file_data(reader)?;
+    self.insert_node(path, Some(Node::new(attrs, Content::File(file))))
+  }
+
+  pub fn add_symlink(&mut self, path: &str, attrs: Attributes, target: &str) -> Result<()> {
+    let node = Node::new(attrs, Content::Symlink(target.as_bytes().to_vec()));
+    self.insert_node(path, Some(node))
+  }
+
+  /// Add a block or character device, a FIFO or a socket. `device` is the device number
+  /// built by `device_number`, it is ignored for FIFOs and sockets.
+  pub fn add_special(
+    &mut self,
+    path: &str,
+    inode_type: InodeType,
+    attrs: Attributes,
+    device: u32,
+  ) -> Result<()> {
+    let content = match inode_type.basic() {
+      InodeType::BasicBlockDevice => Content::BlockDevice(device),
+      InodeType::BasicCharDevice => Content::CharDevice(device),
+      InodeType::BasicFifo => Content::Fifo,
+      InodeType::BasicSocket => Content::Socket,
+      _ => {
+        return Err(io::Error::new(
+          ErrorKind::InvalidInput,
+          format!("{:?} is not a special file type", inode_type),
+        ))
+      }
+    };
+    self.insert_node(path, Some(Node::new(attrs, content)))
+  }
+
+  /// Insert `node` at `path`, or only check it can be inserted if `node` is `None`.
+  fn insert_node(&mut self, path: &str, node: Option<Node>) -> Result<()> {
+    let components = path_components(path)?;
+    let (name, parent) = components.split_last().ok_or_else(|| already_exists(path))?;
+    let children = children_mut(&mut self.root, parent, path)?;
+    if children.contains_key(*name) {
+      return Err(already_exists(path));
+    }
+    if let Some(node) = node {
+      children.insert(name.to_vec(), node);
+    }
+    Ok(())
+  }
+
+  fn write_all(&mut self, data: &[u8]) -> Result<()> {
+    self.out.write_all(data)?;
+    self.pos += data.len() as u64;
+    self.end = self.end.max(self.pos);
+    Ok(())
+  }
+
+  fn compress<'a>(&self, raw: &'a [u8]) -> Result<(Cow<'a, [u8]>, bool)> {
+    compress_block(
+      self.compressor.as_ref(),
+      self.options.compressor_options.as_ref(),
+      raw,
+    )
+  }
+
+  fn meta_writer(&self) -> MetadataWriter {
+    MetadataWriter::with_compressor(
+      self.compressor.clone(),
+      self.options.compressor_options.clone(),
+    )
+  }
+
+  /// Write a data block, returns its on-disk size as stored in the block list of the inode.
+  fn write_data_block(&mut self, raw: &[u8]) -> Result<u32> {
+    if raw.iter().all(|b| *b == 0) {
+      // sparse block
+      return Ok(0);
+    }
+    let (data, compressed) = self.compress(raw)?;
+    let mut size = data.len() as u32;
+    if !compressed {
+      size |= UNCOMPRESSED_BLOCK_FLAG;
+    }
+    self.write_all(&data)?;
+    Ok(size)
+  }
+
+  fn flush_fragment(&mut self) -> Result<()> {
+    if self.fragment_block.is_empty() {
+      return Ok(());
+    }
+    let raw = std::mem::take(&mut self.fragment_block);
+    let (data, compressed) = self.compress(&raw)?;
+    let entry = FragmentEntry {
+      start: self.pos,
+      size: data.len() as u32,
+      compressed,
+    };
+    self.write_all(&data)?;
+    debug!("[flush_fragment] fragment={:?}", entry);
+    self.fragments.push(entry);
+    Ok(())
+  }
+
+  /// Pack a tail end into the current fragment block, returns its index and offset.
+  fn add_fragment(&mut self, tail: &[u8]) -> Result<(u32, u32)> {
+    if self.fragment_block.len() + tail.len() > self.options.block_size as usize {
+      self.flush_fragment()?;
+    }
+    let offset = self.fragment_block.len() as u32;
+    self.fragment_block.extend(tail);
+    Ok((self.fragments.len() as u32, offset))
+  }
+
+  /// Write the content of a regular file. Files smaller than a block are stored in a
+  /// fragment, larger ones end with a short block. Blocks of zeros are not stored.
+  /// A file identical to one already written shares its data.
+  fn write_file_data(&mut self, reader: &mut dyn Read) -> Result<FileData> {
+    let block_size = self.options.block_size as usize;
+    let mut file = FileData {
+      size: 0,
+      blocks_start: self.pos,
+      block_sizes: vec![],
+      sparse: 0,
+      fragment_block_idx: NO_FRAGMENT,
+      fragment_offset: 0,
+    };
+
+    let mut block = vec![0u8; block_size];
+    let mut hasher = DefaultHasher::new();
+    let mut tail = 0;
+    loop {
+      let size = read_block(reader, &mut block)?;
+      if size == 0 {
+        break;
+      }
+      hasher.write(&block[..size]);
+      if size < block_size && file.size == 0 {
+        file.size = size as u64;
+        tail = size;
+        break;
+      }
+
+      file.size += size as u64;
+      let on_disk = self.write_data_block(&block[..size])?;
+      if on_disk == 0 {
+        file.sparse += size as u64;
+      }
+      file.block_sizes.push(on_disk);
+      if size < block_size {
+        break;
+      }
+    }
+
+    let hash = hasher.finish();
+    if let Some(duplicate) = self.find_duplicate(&file, hash, &block[..tail])? {
+      debug!(
+        "[write_file_data] size={}, duplicate of start={}, fragment={}",
+        file.size, duplicate.blocks_start, duplicate.fragment_block_idx
+      );
+      // the data just written is overwritten by the next one
+      self.pos = file.blocks_start;
+      self.out.seek(SeekFrom::Start(self.start + self.pos))?;
+      return Ok(duplicate);
+    }
+    if tail > 0 {
+      let (idx, offset) = self.add_fragment(&block[..tail])?;
+      file.fragment_block_idx = idx;
+      file.fragment_offset = offset;
+    }
+    if self.read_back.is_some() && file.size > 0 {
+      let files = self.duplicates.entry((file.size, hash)).or_default();
+      files.push(file.clone());
+    }
+
+    trace!(
+      "[write_file_data] size={}, start={}, blocks={:?}",
+      file.size,
+      file.blocks_start,
+      file.block_sizes
+    );
+    Ok(file)
+  }
+
+  /// A file already written with the same content as `file`, `tail` is the content of
+  /// `file` if it is stored in a fragment.
+  fn find_duplicate(
+    &mut self,
+    file: &FileData,
+    hash: u64,
+    tail: &[u8],
+  ) -> Result<Option<FileData>> {
+    if self.read_back.is_none() || file.size == 0 {
+      return Ok(None);
+    }
+    let candidates = match self.duplicates.get(&(file.size, hash)) {
+      Some(candidates) => candidates.clone(),
+      None => return Ok(None),
+    };
+    for candidate in candidates {
+      let same = if file.block_sizes.is_empty() {
+        candidate.block_sizes.is_empty() && self.read_fragment(&candidate, tail.len())? == tail
+      } else {
+        self.same_blocks(&candidate, file)?
+      };
+      if same {
+        return Ok(Some(candidate));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Read the `buf.len()` bytes written at `offset`, the output is positioned back
+  /// at the end of the data.
+  fn read_written(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+    let read_back = self
+      .read_back
+      .ok_or_else(|| unsupported_error!("the output can't be read back"))?;
+    let result = read_back(&mut self.out, self.start + offset, buf);
+    self.out.seek(SeekFrom::Start(self.start + self.pos))?;
+    result
+  }
+
+  /// The content of a file stored in a fragment.
+  fn read_fragment(&mut self, file: &FileData, size: usize) -> Result<Vec<u8>> {
+    let offset = file.fragment_offset as usize;
+    let entry = match self.fragments.get(file.fragment_block_idx as usize) {
+      Some(entry) => entry.clone(),
+      // not flushed yet
+      None => return Ok(self.fragment_block[offset..offset + size].to_vec()),
+    };
+    let mut data = vec![0u8; entry.size as usize];
+    self.read_written(entry.start, &mut data)?;
+    if entry.compressed {
+      let mut block = vec![0u8; self.options.block_size as usize];
+      let size = self.compressor.decompress(&data, &mut block)?;
+      block.truncate(size);
+      data = block;
+    }
+    data
+      .get(offset..offset + size)
+      .map(|d| d.to_vec())
+      .ok_or_else(|| invalid_error!(format!("fragment {} is too short", file.fragment_block_idx)))
+  }
+
+  /// Whether the data blocks of two files are the same. Compression is deterministic so
+  /// identical content has identical blocks on disk.
+  fn same_blocks(&mut self, a: &FileData, b: &FileData) -> Result<bool> {
+    if a.block_sizes != b.block_sizes {
+      return Ok(false);
+    }
+    let size: u64 = a
+      .block_sizes
+      .iter()
+      .map(|s| (s & !UNCOMPRESSED_BLOCK_FLAG) as u64)
+      .sum();
+    let chunk = self.options.block_size as u64;
+    let mut offset = 0;
+    while offset < size {
+      let n = chunk.min(size - offset) as usize;
+      let mut data_a = vec![0u8; n];
+      let mut data_b = vec![0u8; n];
+      self.read_written(a.blocks_start + offset, &mut data_a)?;
+      self.read_written(b.blocks_start + offset, &mut data_b)?;
+      if data_a != data_b {
+        return Ok(false);
+      }
+      offset += n as u64;
+    }
+    Ok(true)
+  }
+
+  /// Write `data` as metadata blocks followed by the list of their locations,
+  /// returns the start of the list.
+  fn write_table(&mut self, data: &[u8]) -> Result<u64> {
+    let locations = self.write_metadata(data)?;
+    let start = self.pos;
+    for location in locations {
+      self.write_all(&location.to_le_bytes())?;
+    }
+    Ok(start)
+  }
+
+  /// Write `data` as metadata blocks, returns their locations.
+  fn write_metadata(&mut self, data: &[u8]) -> Result<Vec<u64>> {
+    let mut locations = vec![];
+    for chunk in data.chunks(METADATA_BLOCK_SIZE) {
+      let mut writer = self.meta_writer();
+      writer.write(chunk)?;
+      locations.push(self.pos);
+      self.write_all(&writer.finish()?)?;
+    }
+    Ok(locations)
+  }
+
+  /// Write the tables and the superblock, returns the underlying writer positioned
+  /// after the padded image.
+  pub fn finish(mut self) -> Result<W> {
+    self.flush_fragment()?;
+
+    let mut root = std::mem::replace(
+      &mut self.root,
+      Node::new(Attributes::default(), Content::Fifo),
+    );
+    let inode_count = root.number_inodes(0);
+    let mut tables = Tables {
+      inodes: self.meta_writer(),
+      directories: self.meta_writer(),
+      ids: vec![],
+      id_idx: HashMap::new(),
+      exports: vec![InodeRef::default(); inode_count as usize],
+      xattrs: self.options.xattrs,
+      clamp_time: self.options.clamp_time,
+      uid: self.options.uid,
+      gid: self.options.gid,
+      xattr_kv: self.meta_writer(),
+      xattr_ids: vec![],
+      xattr_idx: HashMap::new(),
+    };
+    // like mksquashfs, the parent of the root is the number after the last inode
+    let root_inode_ref = tables.write_node(&root, inode_count + 1)?;
+
+    let inode_table_start = self.pos;
+    self.write_all(&tables.inodes.finish()?)?;
+    let directory_table_start = self.pos;
+    self.write_all(&tables.directories.finish()?)?;
+
+    let fragments: Vec<u8> = self.fragments.iter().flat_map(|f| f.to_bytes()).collect();
+    let fragment_table_start = self.write_table(&fragments)?;
+
+    let export_table_start = if self.options.exportable {
+      let exports: Vec<u8> = tables.exports.iter().flat_map(|r| r.0.to_le_bytes()).collect();
+      self.write_table(&exports)?
+    } else {
+      NO_TABLE
+    };
+
+    let ids: Vec<u8> = tables.ids.iter().flat_map(|id| id.to_le_bytes()).collect();
+    let id_table_start = self.write_table(&ids)?;
+
+    let xattr_id_table_start = if tables.xattr_ids.is_empty() {
+      NO_TABLE
+    } else {
+      let kv_start = self.pos;
+      self.write_all(&tables.xattr_kv.finish()?)?;
+      let ids: Vec<u8> = tables.xattr_ids.iter().flat_map(|id| id.to_bytes()).collect();
+      let locations = self.write_metadata(&ids)?;
+      let start = self.pos;
+      let header = OnDiskXAttrIdTable::new(kv_start, tables.xattr_ids.len() as u32);
+      self.write_all(&header.to_bytes())?;
+      for location in locations {
+        self.write_all(&location.to_le_bytes())?;
+      }
+      start
+    };
+
+    let mut flags = Flags::empty();
+    if self.options.exportable {
+      flags |= Flags::EXPORTABLE;
+    }
+    if !self.options.xattrs {
+      flags |= Flags::NO_XATTRS;
+    }
+    if self.options.compressor_options.is_some() {
+      flags |= Flags::COMPRESSOR_OPTIONS;
+    }
+    if self.read_back.is_some() {
+      flags |= Flags::DUPLICATES;
+    }
+    let sb = Superblock {
+      magic: MAGIC_NUMBER,
+      inode_count,
+      modification_time: clamp(
+        self.options.modification_time.unwrap_or_default(),
+        self.options.clamp_time,
+      ),
+      block_size: self.options.block_size,
+      fragment_entry_count: self.fragments.len() as u32,
+      compressor: self.options.compressor,
+      block_log: self.options.block_size.trailing_zeros() as u16,
+      flags,
+      id_count: tables.ids.len() as u16,
+      version_major: VERSION_MAJOR,
+      version_minor: VERSION_MINOR,
+      root_inode_ref,
+      bytes_used: self.pos,
+      id_table_start,
+      xattr_id_table_start,
+      inode_table_start,
+      directory_table_start,
+      fragment_table_start,
+      export_table_start,
+      compressor_options: self.options.compressor_options.clone(),
+    };
+    debug!("[finish] superblock={:?}", sb);
+    sb.validate()?;
+
+    // the padding also overwrites what is left of a duplicate rewound at the end of the data
+    let end = self.pos.max(self.end).div_ceil(PAD_SIZE) * PAD_SIZE;
+    let zeros = [0u8; PAD_SIZE as usize];
+    while self.pos < end {
+      let size = (end - self.pos).min(PAD_SIZE);
+      self.write_all(&zeros[..size as usize])?;
+    }
+    self.out.seek(SeekFrom::Start(self.start))?;
+    self.out.write_all(&sb.to_bytes())?;
+    self.out.seek(SeekFrom::Start(self.start + self.pos))?;
+    self.out.flush()?;
+
+    Ok(self.out)
+  }
+}
+
+impl<W: Read + Write + Seek> SquashfsWriter<W> {
+  /// Store identical files once and set `Flags::DUPLICATES`, call it before adding files.
+  ///
+  /// Files with the same size and hash are compared with the data already written,
+  /// which is read back from the output. A duplicate shares the data blocks or the
+  /// fragment of the first file, its own data is overwritten.
+  ///
+  /// Fails if the output can't be read, e.g. a `File` opened write only.
+  pub fn enable_deduplication(&mut self) -> Result<()> {
+    let mut probe = [0u8; 1];
+    let result = read_back(&mut self.out, self.start, &mut probe);
+    self.out.seek(SeekFrom::Start(self.start + self.pos))?;
+    if let Err(e) = result {
+      return Err(unsupported_error!(format!(
+        "deduplication needs an output which can be read back: {}",
+        e
+      )));
+    }
+    self.read_back = Some(read_back::<W>);
+    Ok(())
+  }
+}
+
+fn read_back<W: Read + Seek>(out: &mut W, offset: u64, buf: &mut [u8]) -> Result<()> {
+  out.seek(SeekFrom::Start(offset))?;
+  out.read_exact(buf)
+}
+
+#[cfg(all(test, unix, feature = "gzip"))]
+mod tests {
+  use super::*;
+  use std::fs;
+  use std::io::Cursor;
+  use std::os::unix::fs::{symlink, PermissionsExt};
+  use std::os::unix::net::UnixListener;
+  use std::path::PathBuf;
+
+  /// A directory under the temporary directory, removed when dropped.
+  struct TempDir(PathBuf);
+
+  impl TempDir {
+    fn new(name: &str) -> Result<Self> {
+      let path = std::env::temp_dir().join(format!("squashfs-{}-{}", name, std::process::id()));
+      let _ = fs::remove_dir_all(&path);
+      fs::create_dir_all(&path)?;
+      Ok(TempDir(path))
+    }
+  }
+
+  impl Drop for TempDir {
+    fn drop(&mut self) {
+      let _ = fs::remove_dir_all(&self.0);
+    }
+  }
+
+  fn content(size: usize, seed: u8) -> Vec<u8> {
+    (0..size).map(|i| (i / 7) as u8 ^ seed).collect()
+  }
+
+  #[test]
+  fn test_write_host_dir() -> Result<()> {
+    let dir = TempDir::new("writer")?;
+    let root = &dir.0;
+    fs::create_dir_all(root.join("usr/share/empty"))?;
+    let files = vec![
+      ("empty", vec![]),
+      ("small", content(100, 1)),
+      ("usr/block", content(4096, 2)),
+      ("usr/share/large", content(3 * 4096 + 10, 3)),
+      ("usr/share/sparse", {
+        let mut data = vec![0u8; 3 * 4096];
+        data.extend(content(20, 4));
+        data
+      }),
+    ];
+    for (name, data) in &files {
+      fs::write(root.join(name), data)?;
+    }
+    symlink("usr/share", root.join("share"))?;
+    let _socket = UnixListener::bind(root.join("socket"))?;
+    // tmpfs and most disk filesystems support user xattrs, skip them otherwise
+    let has_xattrs = xattr::set(root.join("small"), "user.test", b"value").is_ok();
+
+    let options = WriterOptions {
+      block_size: 4096,
+      modification_time: Some(0x6000_0000),
+      ..Default::default()
+    };
+    let mut writer = SquashfsWriter::with_options(Cursor::new(vec![]), options)?;
+    writer.add_host_dir(root)?;
+    let image = writer.finish()?.into_inner();
+    assert_eq!(image.len() as u64 % PAD_SIZE, 0);
+
+    let mut sqs = SquashFs::new(Cursor::new(image))?;
+    let sb = sqs.superblock();
+    assert_eq!(sb.inode_count, 11);
+    assert_eq!(sb.modification_time, 0x6000_0000);
+    assert_eq!(sb.fragment_entry_count, 1);
+    assert!(sb.flags.exportable());
+
+    for (name, data) in &files {
+      let mut read = vec![];
+      sqs.open(&format!("/{}", name))?.read_to_end(&mut read)?;
+      assert_eq!(&read, data, "content of {}", name);
+    }
+    let mut read = vec![];
+    sqs.open("/share/large")?.read_to_end(&mut read)?;
+    assert_eq!(read, files[3].1);
+
+    let socket = sqs.lookup("/socket")?;
+    assert_eq!(socket.inode_type(), InodeType::BasicSocket);
+    let sparse = sqs.lookup("/usr/share/sparse")?;
+    if has_xattrs {
+      let small = sqs.lookup("/small")?;
+      assert_eq!(small.inode_type(), InodeType::ExtendedFile);
+      let xattrs = sqs.xattrs(&small)?;
+      assert_eq!(xattrs.len(), 1);
+      assert_eq!(xattrs[0].name, b"user.test");
+      assert_eq!(xattrs[0].value, b"value");
+    }
+    assert_eq!(sparse.inode_type(), InodeType::ExtendedFile);
+
+    let paths: Vec<_> = sqs
+      .walk(WalkOrder::DepthFirst)
+      .map(|e| e.map(|e| e.path_lossy()))
+      .collect::<Result<_>>()?;
+    assert_eq!(
+      paths,
+      vec![
+        "/",
+        "/empty",
+        "/share",
+        "/small",
+        "/socket",
+        "/usr",
+        "/usr/block",
+        "/usr/share",
+        "/usr/share/empty",
+        "/usr/share/large",
+        "/usr/share/sparse",
+      ]
+    );
+    for n in 1..=sqs.superblock().inode_count {
+      let inode_ref = sqs.inode_by_number(n)?;
+      assert_eq!(sqs.inode(inode_ref)?.header().inode_number, n);
+    }
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_merge_host_dir() -> Result<()> {
+    let dir = TempDir::new("merge")?;
+    let root = &dir.0;
+    fs::create_dir_all(root.join("etc"))?;
+    fs::write(root.join("etc/hostname"), b"host\n")?;
+    fs::set_permissions(root.join("etc"), fs::Permissions::from_mode(0o751))?;
+
+    let mut writer = SquashfsWriter::new(Cursor::new(vec![]))?;
+    let attrs = Attributes {
+      permissions: 0o700,
+      ..Default::default()
+    };
+    writer.add_dir("/etc", attrs)?;
+    writer.add_file("/etc/motd", Attributes::default(), &mut &b"hello\n"[..])?;
+    writer.add_symlink("/bin", Attributes::default(), "usr/bin")?;
+    writer.add_host_dir(root)?;
+    let image = writer.finish()?.into_inner();
+
+    let mut sqs = SquashFs::new(Cursor::new(image))?;
+    let etc = sqs.lookup("/etc")?;
+    let names = sqs
+      .read_dir(&etc)?
+      .map(|e| e.map(|e| e.name_lossy()))
+      .collect::<Result<Vec<_>>>()?;
+    assert_eq!(names, vec!["hostname", "motd"]);
+    assert_eq!(etc.header().permissions, 0o751);
+    let bin = sqs.lookup_with("/bin", LookupOptions { follow_symlinks: false })?;
+    assert_eq!(bin.inode_type(), InodeType::BasicSymlink);
+
+    let mut writer = SquashfsWriter::new(Cursor::new(vec![]))?;
+    writer.add_file("/etc", Attributes::default(), &mut &b""[..])?;
+    let err = writer.add_host_dir(root).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_builder() -> Result<()> {
+    let attrs = |permissions, uid, modified_time| Attributes {
+      permissions,
+      uid,
+      gid: 100,
+      modified_time,
+      xattrs: vec![],
+    };
+    let options = WriterOptions {
+      block_size: 4096,
+      exportable: false,
+      modification_time: Some(1000),
+      ..Default::default()
+    };
+    let mut writer = SquashfsWriter::with_options(Cursor::new(vec![]), options)?;
+    writer.add_dir("/", attrs(0o700, 0, 10))?;
+    writer.add_dir("/etc", attrs(0o755, 0, 20))?;
+    let config = content(5000, 5);
+    writer.add_file("/etc/config", attrs(0o600, 1000, 30), &mut Cursor::new(&config))?;
+    let mut xattr_attrs = attrs(0o644, 0, 40);
+    xattr_attrs.xattrs = vec![XAttr {
+      name: b"security.selinux".to_vec(),
+      value: b"system_u:object_r:etc_t:s0".to_vec(),
+    }];
+    writer.add_file("/etc/hosts", xattr_attrs, &mut Cursor::new(b"127.0.0.1 localhost\n"))?;
+    writer.add_symlink("/hosts", attrs(0o777, 0, 50), "etc/hosts")?;
+    writer.add_dir("/dev", attrs(0o755, 0, 60))?;
+    writer.add_special(
+      "/dev/null",
+      InodeType::BasicCharDevice,
+      attrs(0o666, 0, 70),
+      device_number(1, 3),
+    )?;
+    writer.add_special("/dev/fifo", InodeType::ExtendedFifo, attrs(0o600, 0, 80), 0)?;
+
+    let err = writer.add_symlink("/etc/config", attrs(0o777, 0, 0), "x").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    let err = writer.add_dir("/missing/dir", attrs(0o755, 0, 0)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    let err = writer
+      .add_file("/etc/config/x", attrs(0o644, 0, 0), &mut Cursor::new(b""))
+      .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotADirectory);
+    let err = writer
+      .add_special("/dev/dir", InodeType::BasicDirectory, attrs(0o755, 0, 0), 0)
+      .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    let long = format!("/{}", "x".repeat(DIRECTORY_MAX_NAME_LEN + 1));
+    let err = writer.add_dir(&long, attrs(0o755, 0, 0)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    let image = writer.finish()?.into_inner();
+    let mut sqs = SquashFs::new(Cursor::new(image))?;
+    assert!(!sqs.superblock().flags.exportable());
+    assert_eq!(sqs.superblock().modification_time, 1000);
+
+    let root = sqs.root()?;
+    assert_eq!(root.header().permissions, 0o700);
+    assert_eq!(root.header().modified_time, 10);
+
+    let inode = sqs.lookup_with("/hosts", LookupOptions { follow_symlinks: false })?;
+    assert_eq!(inode.symlink_target(), Some(&b"etc/hosts"[..]));
+    let inode = sqs.lookup("/etc/hosts")?;
+    assert_eq!(inode.inode_type(), InodeType::ExtendedFile);
+    assert_eq!(sqs.xattrs(&inode)?[0].name, b"security.selinux");
+
+    let inode = sqs.lookup("/etc/config")?;
+    assert_eq!(inode.inode_type(), InodeType::BasicFile);
+    assert!(sqs.xattrs(&inode)?.is_empty());
+    let header = inode.header().clone();
+    assert_eq!(header.permissions, 0o600);
+    assert_eq!(header.modified_time, 30);
+    assert_eq!(sqs.id(header.uid_idx)?, 1000);
+    assert_eq!(sqs.id(header.gid_idx)?, 100);
+    let mut read = vec![];
+    sqs.open("/etc/config")?.read_to_end(&mut read)?;
+    assert_eq!(read, config);
+
+    match sqs.lookup("/dev/null")? {
+      Inode::BasicCharDevice(_, device) => assert_eq!(device.device, device_number(1, 3)),
+      inode => panic!("unexpected inode {:?}", inode),
+    }
+    assert_eq!(sqs.lookup("/dev/fifo")?.inode_type(), InodeType::BasicFifo);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_reproducible() -> Result<()> {
+    let build = |now: u32, uid: u32| -> Result<Vec<u8>> {
+      let attrs = Attributes {
+        permissions: 0o644,
+        uid,
+        gid: uid,
+        modified_time: now,
+        xattrs: vec![],
+      };
+      let mut options = WriterOptions::reproducible(1_600_000_000);
+      options.modification_time = Some(now);
+      let mut writer = SquashfsWriter::with_options(Cursor::new(vec![]), options)?;
+      writer.add_dir("/b", attrs.clone())?;
+      writer.add_file("/b/data", attrs.clone(), &mut Cursor::new(content(10_000, 6)))?;
+      writer.add_file("/a", attrs, &mut Cursor::new(b"a"))?;
+      Ok(writer.finish()?.into_inner())
+    };
+    let image = build(1_700_000_000, 1000)?;
+    assert_eq!(image, build(1_800_000_000, 2000)?);
+
+    let mut sqs = SquashFs::new(Cursor::new(image))?;
+    assert_eq!(sqs.superblock().modification_time, 1_600_000_000);
+    assert_eq!(sqs.ids()?, &[0]);
+    let inode = sqs.lookup("/b/data")?;
+    assert_eq!(inode.header().modified_time, 1_600_000_000);
+    assert_eq!(sqs.lookup("/a")?.header().inode_number, 1);
+
+    // the same trees created in a different order, so with other host inode numbers
+    // and directory order
+    let first = TempDir::new("reproducible-first")?;
+    fs::write(first.0.join("z"), content(5000, 7))?;
+    fs::write(first.0.join("a"), b"a")?;
+    fs::create_dir(first.0.join("m"))?;
+    fs::write(first.0.join("m/f"), content(100, 8))?;
+    let second = TempDir::new("reproducible-second")?;
+    fs::create_dir(second.0.join("m"))?;
+    fs::write(second.0.join("m/f"), content(100, 8))?;
+    fs::write(second.0.join("a"), b"a")?;
+    fs::write(second.0.join("z"), content(5000, 7))?;
+
+    let build_host = |dir: &TempDir| -> Result<Vec<u8>> {
+      let options = WriterOptions {
+        block_size: 4096,
+        ..WriterOptions::reproducible(1_600_000_000)
+      };
+      let mut writer = SquashfsWriter::with_options(Cursor::new(vec![]), options)?;
+      writer.add_host_dir(&dir.0)?;
+      Ok(writer.finish()?.into_inner())
+    };
+    assert_eq!(build_host(&first)?, build_host(&second)?);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_deduplication() -> Result<()> {
+    // pseudo random data gzip can't shrink
+    let mut state = 7u32;
+    let noise: Vec<u8> = (0..3 * 4096 + 100)
+      .map(|_| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (state >> 16) as u8
+      })
+      .collect();
+    let files: Vec<(&str, &[u8])> = vec![
+      ("/a", &noise),
+      ("/b", &noise),
+      ("/c", b"hello"),
+      ("/filler1", &noise[..4000]),
+      ("/filler2", &noise[1..4001]),
+      ("/d", b"hello"),
+      ("/e", b"world"),
+      ("/z", &noise),
+    ];
+    let build = |deduplicate: bool| -> Result<Vec<u8>> {
+      let options = WriterOptions {
+        block_size: 4096,
+        ..WriterOptions::reproducible(0)
+      };
+      let mut writer = SquashfsWriter::with_options(Cursor::new(vec![]), options)?;
+      if deduplicate {
+        writer.enable_deduplication()?;
+      }
+      for (path, data) in &files {
+        writer.add_file(path, Attributes::default(), &mut Cursor::new(data))?;
+      }
+      Ok(writer.finish()?.into_inner())
+    };
+
+    let sqs = SquashFs::new(Cursor::new(build(false)?))?;
+    assert!(!sqs.superblock().flags.duplicates());
+    let bytes_used = sqs.superblock().bytes_used;
+
+    let mut sqs = SquashFs::new(Cursor::new(build(true)?))?;
+    assert!(sqs.superblock().flags.duplicates());
+    assert!(sqs.superblock().bytes_used <= bytes_used - 2 * noise.len() as u64);
+    for (path, data) in &files {
+      let mut read = vec![];
+      sqs.open(path)?.read_to_end(&mut read)?;
+      assert_eq!(&read, data, "content of {}", path);
+    }
+
+    let mut data = vec![];
+    for path in ["/a", "/b", "/z", "/c", "/d", "/e"] {
+      match sqs.lookup(path)? {
+        Inode::BasicFile(_, file) => {
+          data.push((file.block_idx, file.fragment_block_idx, file.offset))
+        }
+        inode => panic!("unexpected inode {:?}", inode),
+      }
+    }
+    assert_eq!(data[0], data[1]);
+    assert_eq!(data[0], data[2]);
+    assert_eq!(data[3], data[4]);
+    assert_eq!(data[3].1, 0);
+    assert_ne!(data[3], data[5]);
+
+    let dir = TempDir::new("deduplication")?;
+    let mut writer = SquashfsWriter::new(fs::File::create(dir.0.join("image"))?)?;
+    let err = writer.enable_deduplication().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+
+    Ok(())
+  }
+}
@@ -1,12 +1,17 @@
 use super::*;
-use std::io::{Result, SeekFrom};
+use byteorder::{ByteOrder, LittleEndian};
+use std::convert::TryFrom;
+use std::io::{ErrorKind, Result, SeekFrom};
 
 pub const XATTR_IDENTRY_SIZE: usize = 16;
 pub const XATTR_HEADER_SIZE: usize = 16;
 pub const NO_XATTR_INODE_FLAG: u32 = 0xffff_ffff;
 pub const NO_XATTR_SUPERBLOCK_FLAG: u64 = 0xffff_ffff_ffff_ffff;
 
-#[repr(C)]
+/// Set in `XAttrEntry::xtype` if the value is a reference to a value stored elsewhere
+pub const XATTR_VALUE_OOL: u16 = 0x0100;
+pub const XATTR_PREFIX_MASK: u16 = 0x00ff;
+
 #[derive(Debug, Default)]
 pub struct OnDiskXAttrIdTable {
   /// Locaton of first meta block holding the kv pairs.
@@ -17,10 +22,43 @@ pub struct OnDiskXAttrIdTable {
   _padding: u32,
 }
 
-impl_converter!(OnDiskXAttrIdTable);
+impl OnDiskXAttrIdTable {
+  pub fn new(location: u64, count: u32) -> Self {
+    OnDiskXAttrIdTable {
+      location,
+      count,
+      _padding: 0,
+    }
+  }
+
+  pub fn parse(data: &[u8]) -> Result<Self> {
+    if data.len() < XATTR_HEADER_SIZE {
+      return Err(invalid_error!(format!(
+        "invalid xattr id table header size({} bytes), must >= {} bytes",
+        data.len(),
+        XATTR_HEADER_SIZE
+      )));
+    }
+    Ok(OnDiskXAttrIdTable {
+      location: LittleEndian::read_u64(&data[0..8]),
+      count: LittleEndian::read_u32(&data[8..12]),
+      _padding: LittleEndian::read_u32(&data[12..16]),
+    })
+  }
+
+  pub fn to_bytes(&self) -> [u8; XATTR_HEADER_SIZE] {
+    let mut data = [0u8; XATTR_HEADER_SIZE];
+    LittleEndian::write_u64(&mut data[0..8], self.location);
+    LittleEndian::write_u32(&mut data[8..12], self.count);
+    data
+  }
+}
 
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct XAttrIndex {
   /// Location of the 1th kv pair.
+  /// The upper 48 bits are the offset of the metadata block relative to
+  /// `XAttrTable::location`, the lower 16 bits the offset within the block.
   pub location: u64,
   /// Number of kv pairs.
   pub count: u32,
@@ -28,54 +66,506 @@ pub struct XAttrIndex {
   pub size: u32,
 }
 
+impl XAttrIndex {
+  pub fn parse(data: &[u8]) -> Self {
+    XAttrIndex {
+      location: LittleEndian::read_u64(&data[0..8]),
+      count: LittleEndian::read_u32(&data[8..12]),
+      size: LittleEndian::read_u32(&data[12..16]),
+    }
+  }
+
+  pub fn to_bytes(&self) -> [u8; XATTR_IDENTRY_SIZE] {
+    let mut data = [0u8; XATTR_IDENTRY_SIZE];
+    LittleEndian::write_u64(&mut data[0..8], self.location);
+    LittleEndian::write_u32(&mut data[8..12], self.count);
+    LittleEndian::write_u32(&mut data[12..16], self.size);
+    data
+  }
+}
+
+/// The xattr id table, `Inode::xattr_idx` is an index of `list`.
+#[derive(Clone, Debug, Default)]
 pub struct XAttrTable {
+  /// The start of the metadata blocks holding the kv pairs
   pub location: u64,
   pub list: Vec<XAttrIndex>,
-  pub data: Vec<u8>,
+}
+
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum XAttrPrefix {
+  User = 0,
+  Trusted,
+  Security,
+}
+
+impl XAttrPrefix {
+  pub fn as_bytes(&self) -> &'static [u8] {
+    match self {
+      XAttrPrefix::User => b"user.",
+      XAttrPrefix::Trusted => b"trusted.",
+      XAttrPrefix::Security => b"security.",
+    }
+  }
+
+  /// Split a full name into its prefix and the key stored on disk,
+  /// `None` for the namespaces squashfs can't store, e.g. `system.`.
+  pub fn split(name: &[u8]) -> Option<(XAttrPrefix, &[u8])> {
+    [XAttrPrefix::User, XAttrPrefix::Trusted, XAttrPrefix::Security]
+      .iter()
+      .find(|prefix| name.starts_with(prefix.as_bytes()))
+      .map(|prefix| (*prefix, &name[prefix.as_bytes().len()..]))
+  }
+}
+
+impl TryFrom<u16> for XAttrPrefix {
+  type Error = std::io::Error;
+
+  fn try_from(xtype: u16) -> Result<Self> {
+    Ok(match xtype & XATTR_PREFIX_MASK {
+      0 => XAttrPrefix::User,
+      1 => XAttrPrefix::Trusted,
+      2 => XAttrPrefix::Security,
+      n => return Err(invalid_error!(format!("unknown xattr prefix {}", n))),
+    })
+  }
 }
 
 /// Single xattr key.
+#[derive(Clone, Debug, Default)]
 pub struct XAttrEntry {
   /// Encodes the prefix of the key
   pub xtype: u16,
 
   pub size: u16,
 
-  ///
-  pub key: u8,
+  /// The key name, without prefix
+  pub key: Vec<u8>,
 }
 
-/// TODO
-pub fn read_xattrs_table(r: &mut SqsIoReader, sb: Superblock) -> Result<()> {
-  let mut header = OnDiskXAttrIdTable::default();
-  if sb.flags.no_xattrs() {
-    return Ok(());
+impl XAttrEntry {
+  pub fn prefix(&self) -> Result<XAttrPrefix> {
+    XAttrPrefix::try_from(self.xtype)
+  }
+
+  /// The value is the location of the actual value, relative to `XAttrTable::location`.
+  pub fn is_out_of_line(&self) -> bool {
+    self.xtype & XATTR_VALUE_OOL == XATTR_VALUE_OOL
+  }
+
+  /// The full name, e.g. `security.selinux`.
+  pub fn name(&self) -> Result<Vec<u8>> {
+    let mut name = self.prefix()?.as_bytes().to_vec();
+    name.extend(&self.key);
+    Ok(name)
   }
-  warn!(
+}
+
+/// An extended attribute of an inode.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct XAttr {
+  /// The full name, with the `user.`, `trusted.` or `security.` prefix
+  pub name: Vec<u8>,
+  pub value: Vec<u8>,
+}
+
+impl XAttr {
+  pub fn name_lossy(&self) -> String {
+    String::from_utf8_lossy(&self.name).into_owned()
+  }
+}
+
+/// read xattr id table.
+/// 1. Read the header at `Superblock::xattr_id_table_start`;
+/// 2. Read the locations of the metadata blocks holding the ids, they follow the header, 8 bytes each(u64);
+/// 3. Read the ids, 16 bytes each.
+pub fn read_xattrs_table(r: &mut dyn SqsIoRead, sb: &Superblock) -> Result<Option<XAttrTable>> {
+  if sb.flags.no_xattrs() || sb.xattr_id_table_start == NO_XATTR_SUPERBLOCK_FLAG {
+    return Ok(None);
+  }
+  debug!(
     "[read_xattrs_table] xattr_id_table_start={}",
     sb.xattr_id_table_start
   );
-  r.seek(SeekFrom::Start(sb.xattr_id_table_start))
-    .map_err(|e| map_error!(e))?;
-  r.read_exact(&mut header.as_mut())
-    .map_err(|e| map_error!(e))?;
-  warn!("[read_xattrs_table] header={:?}", header);
+  let mut buf = [0u8; XATTR_HEADER_SIZE];
+  r.seek(SeekFrom::Start(sb.xattr_id_table_start))?;
+  r.read_exact(&mut buf)?;
+  let header = OnDiskXAttrIdTable::parse(&buf)?;
+  debug!("[read_xattrs_table] header={:?}", header);
+
+  let count = header.count as usize;
+  let mut table = XAttrTable {
+    location: header.location,
+    list: vec![],
+  };
+  if count == 0 {
+    return Ok(Some(table));
+  }
+
+  let blocks = (count * XATTR_IDENTRY_SIZE - 1) / METADATA_BLOCK_SIZE + 1;
+  // don't trust the count for preallocation, it comes from the image
+  let mut locations = vec![];
+  r.take(blocks as u64 * 8).read_to_end(&mut locations)?;
+  if locations.len() < blocks * 8 {
+    return Err(std::io::Error::new(
+      ErrorKind::UnexpectedEof,
+      format!("xattr id table needs {} block locations", blocks),
+    ));
+  }
+
+  let mut data = vec![];
+  for location in locations.chunks(8) {
+    let (block, _) = read_meta_block(r, sb.compressor, LittleEndian::read_u64(location))?;
+    data.extend(block);
+  }
+  if data.len() < count * XATTR_IDENTRY_SIZE {
+    return Err(invalid_error!(format!(
+      "xattr id table has {} bytes, {} ids need {}",
+      data.len(),
+      count,
+      count * XATTR_IDENTRY_SIZE
+    )));
+  }
 
-  Ok(())
+  table.list = data
+    .chunks(XATTR_IDENTRY_SIZE)
+    .take(count)
+    .map(XAttrIndex::parse)
+    .collect();
+  trace!("[read_xattrs_table] ids={:?}", table.list);
+
+  Ok(Some(table))
+}
+
+/// read the kv pairs of the metadata at `location`, relative to `XAttrTable::location`.
+fn read_kv(
+  r: &mut dyn SqsIoRead,
+  sb: &Superblock,
+  table: &XAttrTable,
+  location: u64,
+  size: usize,
+) -> Result<Vec<u8>> {
+  let location = InodeRef(location);
+  read_metadata(
+    r,
+    sb.compressor,
+    table.location,
+    location.block(),
+    location.offset() as u32,
+    size,
+  )
+}
+
+/// read the extended attributes at `idx` of the xattr id table, e.g. `Inode::xattr_idx`.
+/// `XAttrIndex::size` counts the names with prefix and the out of line values, it is only
+/// a hint of the on-disk size, so more metadata is read until all the pairs are parsed.
+pub fn read_xattrs(
+  r: &mut dyn SqsIoRead,
+  sb: &Superblock,
+  table: &XAttrTable,
+  idx: u32,
+) -> Result<Vec<XAttr>> {
+  let id = table
+    .list
+    .get(idx as usize)
+    .ok_or_else(|| invalid_error!(format!("xattr index {} out of the id table", idx)))?;
+
+  let mut size = id.size as usize;
+  let mut previous = None;
+  let pairs = loop {
+    let data = read_kv(r, sb, table, id.location, size)?;
+    match parse_xattr_pairs(&data, id.count) {
+      Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+        // reading more can only help if the last retry got more data
+        if previous.is_some_and(|previous| data.len() <= previous) {
+          return Err(invalid_error!(format!(
+            "xattrs of index {} are truncated at {} bytes",
+            idx,
+            data.len()
+          )));
+        }
+        previous = Some(data.len());
+        size = (data.len() * 2).max(size + METADATA_BLOCK_SIZE);
+      }
+      result => break result?,
+    }
+  };
+
+  let mut xattrs = Vec::with_capacity(pairs.len());
+  for (entry, value) in pairs {
+    let value = if entry.is_out_of_line() {
+      if value.len() != 8 {
+        return Err(invalid_error!(format!(
+          "out of line xattr value reference has {} bytes",
+          value.len()
+        )));
+      }
+      let location = LittleEndian::read_u64(&value);
+      let header = read_kv(r, sb, table, location, 4)?;
+      let size = LittleEndian::read_u32(&header[0..4]) as usize;
+      let data = read_kv(r, sb, table, location, 4 + size)?;
+      parse_xattr_value(&data)?.0
+    } else {
+      value
+    };
+
+    xattrs.push(XAttr {
+      name: entry.name()?,
+      value,
+    });
+  }
+  trace!("[read_xattrs] idx={}, xattrs={:?}", idx, xattrs);
+
+  Ok(xattrs)
+}
+
+/// parse `count` key and value pairs, the values may be out of line references.
+pub fn parse_xattr_pairs(data: &[u8], count: u32) -> Result<Vec<(XAttrEntry, Vec<u8>)>> {
+  // don't trust the count for preallocation, a pair takes at least 8 bytes
+  let mut pairs = Vec::with_capacity((count as usize).min(data.len() / 8));
+  let mut pos = 0;
+  for _ in 0..count {
+    let (entry, size) = parse_xattr_entry(&data[pos..])?;
+    pos += size;
+    let (value, size) = parse_xattr_value(&data[pos..])?;
+    pos += size;
+    pairs.push((entry, value));
+  }
+  Ok(pairs)
+}
+
+fn truncated(what: &str) -> std::io::Error {
+  std::io::Error::new(ErrorKind::UnexpectedEof, format!("truncated xattr {}", what))
+}
+
+/// parse a key, returns the entry and its size.
+pub fn parse_xattr_entry(data: &[u8]) -> Result<(XAttrEntry, usize)> {
+  if data.len() < 4 {
+    return Err(truncated("key"));
+  }
+  let xtype = LittleEndian::read_u16(&data[0..2]);
+  let size = LittleEndian::read_u16(&data[2..4]);
+  let end = 4 + size as usize;
+  let key = data.get(4..end).ok_or_else(|| truncated("key name"))?.to_vec();
+  Ok((XAttrEntry { xtype, size, key }, end))
+}
+
+/// parse a value, returns the value and its size.
+pub fn parse_xattr_value(data: &[u8]) -> Result<(Vec<u8>, usize)> {
+  if data.len() < 4 {
+    return Err(truncated("value"));
+  }
+  let end = 4 + LittleEndian::read_u32(&data[0..4]) as usize;
+  let value = data.get(4..end).ok_or_else(|| truncated("value"))?.to_vec();
+  Ok((value, end))
+}
+
+/// Encode the kv pairs of an inode, the inverse of `parse_xattr_pairs`. All the values are
+/// stored inline. Returns the pairs and the size stored in `XAttrIndex::size`.
+pub fn write_xattr_pairs(xattrs: &[XAttr]) -> Result<(Vec<u8>, u32)> {
+  let mut data = vec![];
+  let mut size = 0;
+  for xattr in xattrs {
+    let (prefix, key) = XAttrPrefix::split(&xattr.name).ok_or_else(|| {
+      unsupported_error!(format!("xattr {} has an unknown prefix", xattr.name_lossy()))
+    })?;
+    data.extend(&(prefix as u16).to_le_bytes());
+    data.extend(&(key.len() as u16).to_le_bytes());
+    data.extend(key);
+    data.extend(&(xattr.value.len() as u32).to_le_bytes());
+    data.extend(&xattr.value);
+    // like the buffers of listxattr and getxattr, names are nul terminated
+    size += xattr.name.len() + 1 + xattr.value.len();
+  }
+  Ok((data, size as u32))
 }
 
 #[cfg(test)]
 mod tests {
   use crate::tests::*;
   use crate::*;
-  use std::io::Result;
+  use byteorder::{LittleEndian, WriteBytesExt};
+  use std::io::{Cursor, Result};
 
   #[test]
   #[cfg_attr(not(feature = "gzip-sqs"), ignore)]
   fn test_read_xattrs_table() -> Result<()> {
-    let (mut reader, sb) = prepare_tests().map_err(|e| map_error!(e))?;
+    let (mut reader, sb) = prepare_tests()?;
+
+    read_xattrs_table(&mut reader, &sb)?;
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_read_xattrs() -> Result<()> {
+    fn key(buf: &mut Vec<u8>, xtype: u16, name: &str) {
+      buf.write_u16::<LittleEndian>(xtype).unwrap();
+      buf.write_u16::<LittleEndian>(name.len() as u16).unwrap();
+      buf.extend(name.as_bytes());
+    }
+    fn value(buf: &mut Vec<u8>, value: &[u8]) {
+      buf.write_u32::<LittleEndian>(value.len() as u32).unwrap();
+      buf.extend(value);
+    }
+
+    // the kv pairs of two inodes, the second one refers to the value of the first
+    let mut kv = vec![];
+    key(&mut kv, 2, "selinux");
+    value(&mut kv, b"system_u:object_r:bin_t:s0\0");
+    key(&mut kv, 0, "mime");
+    let mime = kv.len() as u64;
+    value(&mut kv, b"text/plain");
+    let first_size = kv.len() as u32;
+    let first = b"security.selinux\0user.mime\0".len() as u32 + 27 + 10;
+    key(&mut kv, 1 | XATTR_VALUE_OOL, "overlay.opaque");
+    value(&mut kv, &mime.to_le_bytes());
+    let second = b"trusted.overlay.opaque\0".len() as u32 + 10;
+
+    let mut image = vec![0u8; 8];
+    let kv_start = image.len() as u64;
+    image.write_u16::<LittleEndian>(kv.len() as u16 | 0x8000)?;
+    image.extend(&kv);
+
+    let ids_start = image.len() as u64;
+    let mut ids = vec![];
+    for (location, count, size) in [(0u64, 2u32, first), (first_size as u64, 1, second)] {
+      ids.write_u64::<LittleEndian>(location)?;
+      ids.write_u32::<LittleEndian>(count)?;
+      ids.write_u32::<LittleEndian>(size)?;
+    }
+    image.write_u16::<LittleEndian>(ids.len() as u16 | 0x8000)?;
+    image.extend(&ids);
+
+    let table_start = image.len() as u64;
+    image.write_u64::<LittleEndian>(kv_start)?;
+    image.write_u32::<LittleEndian>(2)?;
+    image.write_u32::<LittleEndian>(0)?;
+    image.write_u64::<LittleEndian>(ids_start)?;
+
+    let sb = Superblock {
+      compressor: compress::Algorithm::Gzip,
+      xattr_id_table_start: table_start,
+      ..Default::default()
+    };
+    let mut cursor = Cursor::new(image);
+    let table = read_xattrs_table(&mut cursor, &sb)?.unwrap();
+    assert_eq!(table.location, kv_start);
+    assert_eq!(table.list.len(), 2);
+
+    let xattrs = read_xattrs(&mut cursor, &sb, &table, 0)?;
+    let names: Vec<_> = xattrs.iter().map(|x| x.name_lossy()).collect();
+    assert_eq!(names, vec!["security.selinux", "user.mime"]);
+    assert_eq!(xattrs[1].value, b"text/plain");
+
+    let xattrs = read_xattrs(&mut cursor, &sb, &table, 1)?;
+    assert_eq!(xattrs[0].name_lossy(), "trusted.overlay.opaque");
+    assert_eq!(xattrs[0].value, b"text/plain");
+
+    assert!(read_xattrs(&mut cursor, &sb, &table, 2).is_err());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_read_xattrs_empty_size() -> Result<()> {
+    // an id with no size pointing at the end of the only kv block
+    let kv = [0x0, 0x0, 0x4, 0x0, b'm', b'i', b'm', b'e'];
+    let mut image = vec![];
+    image.write_u16::<LittleEndian>(kv.len() as u16 | 0x8000)?;
+    image.extend(&kv);
+
+    let ids_start = image.len() as u64;
+    image.write_u16::<LittleEndian>(XATTR_IDENTRY_SIZE as u16 | 0x8000)?;
+    image.write_u64::<LittleEndian>(kv.len() as u64)?;
+    image.write_u32::<LittleEndian>(1)?;
+    image.write_u32::<LittleEndian>(0)?;
+
+    let table_start = image.len() as u64;
+    image.write_u64::<LittleEndian>(0)?;
+    image.write_u32::<LittleEndian>(1)?;
+    image.write_u32::<LittleEndian>(0)?;
+    image.write_u64::<LittleEndian>(ids_start)?;
+
+    let sb = Superblock {
+      compressor: compress::Algorithm::Gzip,
+      xattr_id_table_start: table_start,
+      ..Default::default()
+    };
+    let mut cursor = Cursor::new(image);
+    let table = read_xattrs_table(&mut cursor, &sb)?.unwrap();
+    assert!(read_xattrs(&mut cursor, &sb, &table, 0).is_err());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_huge_xattr_counts() -> Result<()> {
+    let mut image = vec![];
+    image.write_u64::<LittleEndian>(0)?;
+    image.write_u32::<LittleEndian>(u32::MAX)?;
+    image.write_u32::<LittleEndian>(0)?;
+    image.write_u64::<LittleEndian>(0)?;
+
+    let sb = Superblock {
+      compressor: compress::Algorithm::Gzip,
+      xattr_id_table_start: 0,
+      ..Default::default()
+    };
+    assert!(read_xattrs_table(&mut Cursor::new(image), &sb).is_err());
+    assert!(parse_xattr_pairs(&[0x0, 0x0, 0x1, 0x0, b'a'], u32::MAX).is_err());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_parse_xattr_id_table() -> Result<()> {
+    let raw = [
+      0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, 0x03, 0x02, 0x01, 0x0, 0x0, 0x0, 0x0, 0x0,
+    ];
+    let header = OnDiskXAttrIdTable::parse(&raw)?;
+    assert_eq!(header.location, 0x0102_0304_0506_0708);
+    assert_eq!(header.count, 0x01_0203);
+    assert_eq!(header.to_bytes(), raw);
+    assert!(OnDiskXAttrIdTable::parse(&raw[..8]).is_err());
+
+    let raw = [
+      0x10, 0x00, 0x20, 0x0, 0x0, 0x0, 0x0, 0x0, 0x02, 0x01, 0x0, 0x0, 0x34, 0x12, 0x0, 0x0,
+    ];
+    let index = XAttrIndex::parse(&raw);
+    assert_eq!(index.location, 0x20_0010);
+    assert_eq!(index.count, 0x0102);
+    assert_eq!(index.size, 0x1234);
+    assert_eq!(index.to_bytes(), raw);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_write_xattr_pairs() -> Result<()> {
+    let xattrs = vec![
+      XAttr {
+        name: b"security.selinux".to_vec(),
+        value: b"system_u:object_r:bin_t:s0\0".to_vec(),
+      },
+      XAttr {
+        name: b"user.mime".to_vec(),
+        value: b"text/plain".to_vec(),
+      },
+    ];
+    let (data, size) = write_xattr_pairs(&xattrs)?;
+    assert_eq!(size, b"security.selinux\0user.mime\0".len() as u32 + 27 + 10);
+    let pairs = parse_xattr_pairs(&data, 2)?;
+    assert_eq!(pairs[0].0.xtype, XAttrPrefix::Security as u16);
+    assert_eq!(pairs[0].0.name()?, xattrs[0].name);
+    assert_eq!(pairs[1].1, xattrs[1].value);
 
-    read_xattrs_table(&mut reader, sb).map_err(|e| map_error!(e))?;
+    let system = XAttr {
+      name: b"system.posix_acl_access".to_vec(),
+      value: vec![],
+    };
+    assert!(write_xattr_pairs(&[system]).is_err());
 
     Ok(())
   }